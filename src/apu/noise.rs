@@ -0,0 +1,165 @@
+use super::{Envelope, Length};
+
+/// Base periods of the noise clock, in cycles, chosen by NR43 bits 0-2
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Channel 4, sounding the low bit of a linear feedback shift register
+pub struct Noise {
+    length:     Length,
+    envelope:   Envelope,
+    /// The NR43 register
+    reg:        u8,
+    lfsr:       u16,
+    /// Cycles until the next shift of the LFSR
+    timer:      u32,
+    enabled:    bool,
+}
+
+impl Noise {
+
+    pub fn new() -> Noise {
+        Noise {
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            reg: 0,
+            lfsr: 0x7FFF,
+            timer: 8,
+            enabled: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.reg & 0x07) as usize] << (self.reg >> 4)
+    }
+
+    /// Clock shifts of 14 and 15 stop the LFSR altogether
+    fn clocked(&self) -> bool {
+        (self.reg >> 4) < 14
+    }
+
+    /// Write the channel's `index`th register, from NR40 to NR44
+    pub fn write(&mut self, index: u8, data: u8) {
+        match index {
+            1 => self.length.load(data & 0x3F),
+            2 => {
+                self.envelope.reg = data;
+                if !self.envelope.dac_on() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.reg = data,
+            4 => {
+                self.length.enabled = (data & 0x40) != 0;
+                if (data & 0x80) != 0 {
+                    self.enabled = self.envelope.dac_on();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Shift the LFSR once. The xor of the two low bits goes into bit 14,
+    /// and also bit 6 in 7-bit mode.
+    fn shift(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if (self.reg & 0x08) != 0 {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.clocked() {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.shift();
+        }
+        self.timer -= cycles;
+    }
+
+    /// Amplitude fed to the DAC, from 0 to 15, or None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_on() {
+            None
+        } else if self.enabled && (self.lfsr & 1) == 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lfsr_sequence() {
+        let mut ch = Noise::new();
+        ch.write(2, 0xF0);
+        ch.write(4, 0x80);
+        for _ in 0..3 {
+            ch.shift();
+        }
+        assert_eq!(ch.lfsr, 0x0FFF);
+        // 15-bit mode repeats every 32767 shifts
+        for _ in 3..32767 {
+            ch.shift();
+        }
+        assert_eq!(ch.lfsr, 0x7FFF);
+        // 7-bit mode every 127
+        ch.write(3, 0x08);
+        let mut seen = Vec::new();
+        for _ in 0..254 {
+            ch.shift();
+            seen.push(ch.lfsr & 0x7F);
+        }
+        assert_eq!(&seen[..127], &seen[127..]);
+        // Divisor 48 shifted left by 2
+        ch.write(3, 0x23);
+        assert_eq!(ch.period(), 192);
+    }
+
+    #[test]
+    fn high_shifts_stop_clock() {
+        let mut ch = Noise::new();
+        ch.write(2, 0xF0);
+        ch.write(3, 0xD0);
+        ch.write(4, 0x80);
+        ch.tick(8 << 13);
+        assert_eq!(ch.lfsr, 0x3FFF);
+        for &reg in [0xE0, 0xF7].iter() {
+            ch.write(3, reg);
+            ch.write(4, 0x80);
+            ch.tick(1 << 24);
+            assert_eq!(ch.lfsr, 0x7FFF);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use super::{Envelope, Length};
+
+/// Waveforms for each duty setting, played from bit 0 upwards
+const DUTY_PATTERNS: [u8; 4] = [
+    0b10000000, // 12.5%
+    0b10000001, // 25%
+    0b11100001, // 50%
+    0b01111110, // 75%
+];
+
+/// Frequency sweep of channel 1
+struct Sweep {
+    /// The NR10 register
+    reg:        u8,
+    enabled:    bool,
+    /// Frequency being swept, separate from the channel's
+    shadow:     u16,
+    timer:      u8,
+}
+
+impl Sweep {
+
+    fn period(&self) -> u8 {
+        (self.reg >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.reg & 0x07
+    }
+
+    /// A period of 0 counts as 8
+    fn reload(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            p => p,
+        };
+    }
+
+    /// Next frequency of the sweep, which may be out of range
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if (self.reg & 0x08) != 0 {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+}
+
+/// Square wave channel, with a sweep on channel 1
+pub struct Square {
+    sweep:      Option<Sweep>,
+    length:     Length,
+    envelope:   Envelope,
+    duty:       u8,
+    frequency:  u16,
+    /// Cycles until the next step through the waveform
+    timer:      u32,
+    position:   u8,
+    enabled:    bool,
+}
+
+impl Square {
+
+    pub fn new(with_sweep: bool) -> Square {
+        let sweep = if with_sweep {
+            Some(Sweep {
+                reg: 0,
+                enabled: false,
+                shadow: 0,
+                timer: 0,
+            })
+        } else {
+            None
+        };
+        Square {
+            sweep: sweep,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            frequency: 0,
+            timer: 8192,
+            position: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    /// Write the channel's `index`th register, from NRx0 to NRx4
+    pub fn write(&mut self, index: u8, data: u8) {
+        match index {
+            0 => {
+                if let Some(ref mut sweep) = self.sweep {
+                    sweep.reg = data;
+                }
+            },
+            1 => {
+                self.duty = data >> 6;
+                self.length.load(data & 0x3F);
+            },
+            2 => {
+                self.envelope.reg = data;
+                if !self.envelope.dac_on() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = (data & 0x40) != 0;
+                if (data & 0x80) != 0 {
+                    self.trigger();
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.shadow = frequency;
+            sweep.reload();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // Overflow is checked straight away, but nothing is written back
+            if sweep.shift() != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match self.sweep {
+            Some(ref mut s) => s,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The next step is checked for overflow too
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x07;
+        }
+        self.timer -= cycles;
+    }
+
+    /// Amplitude fed to the DAC, from 0 to 15, or None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_on() {
+            None
+        } else if self.enabled && (DUTY_PATTERNS[self.duty as usize] >> self.position) & 1 != 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_overflow() {
+        let mut ch = Square::new(true);
+        ch.write(2, 0xF0);
+        // Period 1, adding a quarter each step
+        ch.write(0, 0x12);
+        ch.write(3, 0x00);
+        ch.write(4, 0x84);
+        assert!(ch.enabled());
+        ch.clock_sweep();
+        ch.clock_sweep();
+        assert_eq!(ch.frequency, 0x640);
+        assert!(ch.enabled());
+        // 0x7D0 + 0x1F4 overflows on the check after the write back
+        ch.clock_sweep();
+        assert_eq!(ch.frequency, 0x7D0);
+        assert!(!ch.enabled());
+        // Subtracting never overflows
+        ch.write(0, 0x1A);
+        ch.write(4, 0x87);
+        ch.clock_sweep();
+        assert_eq!(ch.frequency, 0x7D0 - 0x1F4);
+        assert!(ch.enabled());
+    }
+
+    #[test]
+    fn envelope() {
+        let mut ch = Square::new(false);
+        // Start at 2, rising every 2 clocks
+        ch.write(2, 0x2A);
+        ch.write(4, 0x80);
+        assert_eq!(ch.envelope.volume, 2);
+        ch.clock_envelope();
+        assert_eq!(ch.envelope.volume, 2);
+        ch.clock_envelope();
+        assert_eq!(ch.envelope.volume, 3);
+        for _ in 0..40 {
+            ch.clock_envelope();
+        }
+        assert_eq!(ch.envelope.volume, 15);
+        // Falling every clock
+        ch.write(2, 0x31);
+        ch.write(4, 0x80);
+        ch.clock_envelope();
+        ch.clock_envelope();
+        assert_eq!(ch.envelope.volume, 1);
+        ch.clock_envelope();
+        ch.clock_envelope();
+        assert_eq!(ch.envelope.volume, 0);
+    }
+
+    #[test]
+    fn duty_waveform() {
+        let mut ch = Square::new(false);
+        ch.write(1, 0x40);
+        ch.write(2, 0xF0);
+        ch.write(3, 0xFF);
+        ch.write(4, 0x87);
+        // Period of 4 cycles per step
+        let mut wave = Vec::new();
+        for _ in 0..8 {
+            ch.tick(4);
+            wave.push(ch.output().unwrap());
+        }
+        assert_eq!(wave, vec![0, 0, 0, 0, 0, 0, 15, 15]);
+    }
+}
@@ -0,0 +1,167 @@
+use super::Length;
+
+/// Channel 3, playing 32 4-bit samples from wave RAM
+pub struct Wave {
+    ram:        [u8; 16],
+    dac_on:     bool,
+    length:     Length,
+    /// Right shift applied to each sample, 4 for silence
+    shift:      u8,
+    frequency:  u16,
+    /// Cycles until the next sample
+    timer:      u32,
+    /// Sample being played, from 0 to 31
+    position:   u8,
+    enabled:    bool,
+}
+
+impl Wave {
+
+    pub fn new() -> Wave {
+        Wave {
+            ram: [0; 16],
+            dac_on: false,
+            length: Length::new(256),
+            shift: 4,
+            frequency: 0,
+            timer: 4096,
+            position: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub fn read_ram(&self, offset: u16) -> u8 {
+        self.ram[offset as usize & 0x0F]
+    }
+
+    pub fn write_ram(&mut self, offset: u16, data: u8) {
+        self.ram[offset as usize & 0x0F] = data;
+    }
+
+    /// Write the channel's `index`th register, from NR30 to NR34
+    pub fn write(&mut self, index: u8, data: u8) {
+        match index {
+            0 => {
+                self.dac_on = (data & 0x80) != 0;
+                if !self.dac_on {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.load(data),
+            2 => {
+                self.shift = match (data >> 5) & 0x03 {
+                    0 => 4,
+                    code => code - 1,
+                };
+            },
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = (data & 0x40) != 0;
+                if (data & 0x80) != 0 {
+                    self.enabled = self.dac_on;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    // Playback starts from the second sample
+                    self.position = 1;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+        }
+        self.timer -= cycles;
+    }
+
+    /// Amplitude fed to the DAC, from 0 to 15, or None while the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac_on {
+            None
+        } else if self.enabled {
+            // The high nibble of each byte plays first
+            let byte = self.ram[self.position as usize / 2];
+            let sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+            Some(sample >> self.shift)
+        } else {
+            Some(0)
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_and_volume() {
+        let mut ch = Wave::new();
+        for i in 0..16 {
+            ch.write_ram(i, 0xF7);
+        }
+        ch.write(0, 0x80);
+        ch.write(2, 0x20);
+        ch.write(3, 0xFF);
+        ch.write(4, 0x87);
+        assert_eq!(ch.output(), Some(7));
+        ch.tick(2);
+        assert_eq!(ch.output(), Some(15));
+        // Half volume
+        ch.write(2, 0x40);
+        assert_eq!(ch.output(), Some(7));
+        ch.tick(2);
+        assert_eq!(ch.output(), Some(3));
+        ch.write(2, 0x00);
+        assert_eq!(ch.output(), Some(0));
+        ch.write(0, 0x00);
+        assert_eq!(ch.output(), None);
+        assert!(!ch.enabled());
+    }
+
+    #[test]
+    fn trigger_starts_at_second_sample() {
+        let mut ch = Wave::new();
+        for i in 0..16 {
+            ch.write_ram(i, ((i as u8 * 2) << 4) | ((i as u8 * 2 + 1) & 0x0F));
+        }
+        ch.write(0, 0x80);
+        ch.write(2, 0x20);
+        ch.write(3, 0xFF);
+        ch.write(4, 0x87);
+        assert_eq!(ch.output(), Some(1));
+        ch.tick(2);
+        assert_eq!(ch.output(), Some(2));
+        // Sample 0 comes round only after the other 31
+        ch.tick(2 * 30);
+        assert_eq!(ch.output(), Some(0));
+        // Triggering again restarts from sample 1
+        ch.tick(2 * 4);
+        ch.write(4, 0x87);
+        assert_eq!(ch.output(), Some(1));
+    }
+}
@@ -0,0 +1,335 @@
+//! Audio output
+//!
+//! Frontends pass the machine's samples on to an `AudioSink`. Sinks which play
+//! in real time hold a short queue, and block the emulation while it is full,
+//! so the sound card's clock paces the machine. To keep that queue from
+//! running dry or overflowing as the two clocks drift, `RateControl` nudges the
+//! APU's sample rate up or down by a fraction of a percent, depending on how
+//! full the queue is. The change in pitch is far too small to hear.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Seek, SeekFrom, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Destination for interleaved 16-bit stereo samples, left first
+pub trait AudioSink {
+
+    /// Rate the samples are played at, in Hz
+    fn sample_rate(&self) -> u32;
+
+    /// Queue samples for output. Sinks which play in real time block here
+    /// until their queue has drained to its target length.
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// How full the output queue is, relative to its target length, for sinks
+    /// which play in real time. None for sinks which take samples as fast as
+    /// they come.
+    fn fill(&self) -> Option<f64> {
+        None
+    }
+
+    /// Flush anything buffered, once the last samples are written
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+/// Sink which throws every sample away
+pub struct NullSink {
+    sample_rate:    u32,
+}
+
+impl NullSink {
+
+    pub fn new(sample_rate: u32) -> NullSink {
+        NullSink {
+            sample_rate: sample_rate,
+        }
+    }
+
+}
+
+impl AudioSink for NullSink {
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+
+}
+
+/// Length of the RIFF header before the sample data
+const WAV_HEADER_LEN: u32 = 44;
+
+/// Sink writing a 16-bit stereo PCM WAV file. The lengths in the header are
+/// filled in by `finish`.
+pub struct WavSink<W: Write + Seek> {
+    out:            W,
+    sample_rate:    u32,
+    data_len:       u32,
+}
+
+impl<W: Write + Seek> WavSink<W> {
+
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavSink<W>> {
+        try!(write_wav_header(&mut out, sample_rate, 0));
+        Ok(WavSink {
+            out: out,
+            sample_rate: sample_rate,
+            data_len: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            bytes.push(sample as u8);
+            bytes.push((sample >> 8) as u8);
+        }
+        try!(self.out.write_all(&bytes));
+        self.data_len += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        try!(self.out.seek(SeekFrom::Start(0)));
+        try!(write_wav_header(&mut self.out, self.sample_rate, self.data_len));
+        try!(self.out.seek(SeekFrom::Start((WAV_HEADER_LEN + self.data_len) as u64)));
+        self.out.flush()
+    }
+
+}
+
+fn write_wav_header<W: Write>(out: &mut W, sample_rate: u32, data_len: u32) -> io::Result<()> {
+    let channels = 2;
+    let bytes_per_frame = channels * 2;
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+    let mut put = |bytes: &[u8]| header.extend_from_slice(bytes);
+    put(b"RIFF");
+    put(&le32(WAV_HEADER_LEN - 8 + data_len));
+    put(b"WAVE");
+    put(b"fmt ");
+    put(&le32(16));
+    // Uncompressed PCM
+    put(&le16(1));
+    put(&le16(channels));
+    put(&le32(sample_rate));
+    put(&le32(sample_rate * bytes_per_frame as u32));
+    put(&le16(bytes_per_frame));
+    put(&le16(16));
+    put(b"data");
+    put(&le32(data_len));
+    out.write_all(&header)
+}
+
+fn le16(x: u16) -> [u8; 2] {
+    [x as u8, (x >> 8) as u8]
+}
+
+fn le32(x: u32) -> [u8; 4] {
+    [x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8]
+}
+
+/// Longest a queue waits for the output to drain before giving up, in case
+/// the device has stalled
+const MAX_QUEUE_WAIT: Duration = Duration::from_millis(250);
+
+/// Most samples a queue holds, as a multiple of its target length. Anything
+/// older is dropped.
+const MAX_QUEUE_FACTOR: usize = 4;
+
+struct SharedQueue {
+    samples:    Mutex<VecDeque<i16>>,
+    drained:    Condvar,
+}
+
+/// Create a queue between the emulation and an audio callback, holding about
+/// `latency_ms` of sound. Backends keep the reader, and play what it returns.
+pub fn sample_queue(sample_rate: u32, latency_ms: u32) -> (QueueSink, QueueReader) {
+    let shared = Arc::new(SharedQueue {
+        samples: Mutex::new(VecDeque::new()),
+        drained: Condvar::new(),
+    });
+    // Whole stereo frames, and at least one of them
+    let target = ((sample_rate as usize * latency_ms as usize / 1000) * 2).max(2);
+    let sink = QueueSink {
+        shared: shared.clone(),
+        sample_rate: sample_rate,
+        target: target,
+    };
+    (sink, QueueReader { shared: shared })
+}
+
+/// Real time sink feeding a `QueueReader` on another thread
+pub struct QueueSink {
+    shared:         Arc<SharedQueue>,
+    sample_rate:    u32,
+    /// Samples to keep queued
+    target:         usize,
+}
+
+impl AudioSink for QueueSink {
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut queue = self.shared.samples.lock().unwrap();
+        queue.extend(samples.iter().cloned());
+        let max = self.target * MAX_QUEUE_FACTOR;
+        if queue.len() > max {
+            let excess = queue.len() - max;
+            queue.drain(..excess);
+        }
+        while queue.len() > self.target {
+            let (q, timeout) = self.shared.drained.wait_timeout(queue, MAX_QUEUE_WAIT).unwrap();
+            queue = q;
+            if timeout.timed_out() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill(&self) -> Option<f64> {
+        let queued = self.shared.samples.lock().unwrap().len();
+        Some(queued as f64 / self.target as f64)
+    }
+
+}
+
+/// Playing end of a sample queue
+pub struct QueueReader {
+    shared:     Arc<SharedQueue>,
+}
+
+impl QueueReader {
+
+    /// Fill `out` with the oldest queued samples, padding with silence if the
+    /// queue runs dry. Returns the number of samples taken from the queue.
+    pub fn read(&mut self, out: &mut [i16]) -> usize {
+        let mut queue = self.shared.samples.lock().unwrap();
+        let count = out.len().min(queue.len());
+        for (x, sample) in out.iter_mut().zip(queue.drain(..count)) {
+            *x = sample;
+        }
+        for x in out[count..].iter_mut() {
+            *x = 0;
+        }
+        self.shared.drained.notify_all();
+        count
+    }
+
+}
+
+/// Largest change to the sample rate, as a fraction of it
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+
+/// Dynamic rate control, which picks the APU's sample rate from how full a
+/// real time sink's queue is
+pub struct RateControl {
+    base_rate:  u32,
+    max_adjust: f64,
+}
+
+impl RateControl {
+
+    pub fn new(base_rate: u32) -> RateControl {
+        RateControl {
+            base_rate: base_rate,
+            max_adjust: MAX_RATE_ADJUST,
+        }
+    }
+
+    /// Rate to sample at given the queue's fill, where 1.0 is its target
+    /// length. A short queue gets slightly more samples per emulated second,
+    /// and a long one slightly fewer, steering it back towards the target.
+    pub fn rate(&self, fill: f64) -> u32 {
+        let adjust = (1.0 - fill).clamp(-1.0, 1.0) * self.max_adjust;
+        (self.base_rate as f64 * (1.0 + adjust)).round() as u32
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::thread;
+
+    #[test]
+    fn wav_file() {
+        let mut wav = WavSink::new(Cursor::new(Vec::new()), 32768).unwrap();
+        wav.write(&[1, -2, 0x1234, -0x8000]).unwrap();
+        wav.finish().unwrap();
+        let data = wav.into_inner().into_inner();
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &[44, 0, 0, 0]);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        // Stereo at 32768 Hz, 4 bytes per frame, 16 bits
+        assert_eq!(&data[22..24], &[2, 0]);
+        assert_eq!(&data[24..28], &[0x00, 0x80, 0, 0]);
+        assert_eq!(&data[28..32], &[0x00, 0x00, 0x02, 0]);
+        assert_eq!(&data[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&data[36..44], b"data\x08\x00\x00\x00");
+        assert_eq!(&data[44..], &[1, 0, 0xFE, 0xFF, 0x34, 0x12, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn queue_paces_writer() {
+        // Ten frames of latency
+        let (mut sink, mut reader) = sample_queue(1000, 10);
+        assert_eq!(sink.fill(), Some(0.0));
+        sink.write(&[1; 16]).unwrap();
+        assert_eq!(sink.fill(), Some(0.8));
+        let mut out = [9; 12];
+        assert_eq!(reader.read(&mut out), 12);
+        assert_eq!(out, [1; 12]);
+        // Underruns play silence
+        assert_eq!(reader.read(&mut out), 4);
+        assert_eq!(&out[..], &[1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        // Writing past the target blocks until the reader catches up
+        let player = thread::spawn(move || {
+            let mut out = [0; 10];
+            let mut total = 0;
+            while total < 30 {
+                thread::sleep(Duration::from_millis(5));
+                total += reader.read(&mut out);
+            }
+            total
+        });
+        sink.write(&[2; 50]).unwrap();
+        assert!(sink.fill().unwrap() <= 1.0);
+        assert!(player.join().unwrap() >= 30);
+    }
+
+    #[test]
+    fn rate_control() {
+        let drc = RateControl::new(48000);
+        assert_eq!(drc.rate(1.0), 48000);
+        assert_eq!(drc.rate(0.0), 48240);
+        assert_eq!(drc.rate(1.5), 47880);
+        // Never more than the largest adjustment
+        assert_eq!(drc.rate(10.0), 47760);
+    }
+}
@@ -0,0 +1,314 @@
+//! The cartridge header, at 0x0100-0x014F of every ROM
+
+use std::fmt;
+use std::io;
+
+use cart::{ram_size, save, ROM_BANK_SIZE};
+
+pub const HEADER_LOGO: usize            = 0x104;
+pub const HEADER_TITLE: usize           = 0x134;
+pub const HEADER_MANUFACTURER: usize    = 0x13F;
+pub const HEADER_CGB_FLAG: usize        = 0x143;
+pub const HEADER_NEW_LICENSEE: usize    = 0x144;
+pub const HEADER_SGB_FLAG: usize        = 0x146;
+pub const HEADER_CART_TYPE: usize       = 0x147;
+pub const HEADER_ROM_SIZE: usize        = 0x148;
+pub const HEADER_RAM_SIZE: usize        = 0x149;
+pub const HEADER_OLD_LICENSEE: usize    = 0x14B;
+pub const HEADER_VERSION: usize         = 0x14C;
+pub const HEADER_CHECKSUM: usize        = 0x14D;
+pub const HEADER_GLOBAL_CHECKSUM: usize = 0x14E;
+pub const HEADER_END: usize             = 0x150;
+
+/// The logo checked by the boot ROM, which must be present for a cartridge
+/// to run on real hardware
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CgbSupport {
+    /// Monochrome game
+    None,
+    /// Enhanced for the CGB, but still runs on a DMG
+    Compatible,
+    /// Only runs on a CGB
+    Only,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Licensee {
+    /// One byte code, from before the two character codes were introduced
+    Old(u8),
+    /// Two character code, used when the old code is 33h
+    New(String),
+}
+
+/// Decoded cartridge header
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title:              String,
+    /// Four character code, only present in later cartridges
+    pub manufacturer:       Option<String>,
+    pub cgb:                CgbSupport,
+    pub sgb:                bool,
+    pub cart_type:          u8,
+    /// ROM size in bytes, or None for an unknown size code
+    pub rom_size:           Option<usize>,
+    /// External RAM size in bytes
+    pub ram_size:           usize,
+    pub licensee:           Licensee,
+    pub version:            u8,
+    pub header_checksum:    u8,
+    pub global_checksum:    u16,
+    header_checksum_ok:     bool,
+    global_checksum_ok:     bool,
+    logo_ok:                bool,
+}
+
+impl CartridgeHeader {
+
+    /// Decode the header of a complete ROM image, checking the logo and
+    /// checksums along the way
+    pub fn parse(rom: &[u8]) -> io::Result<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ROM is too small to hold a header"));
+        }
+        let cgb = match rom[HEADER_CGB_FLAG] {
+            0xC0 => CgbSupport::Only,
+            0x80 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+        // Later cartridges shortened the title to fit the CGB flag, and then
+        // the manufacturer code
+        let manufacturer_bytes = &rom[HEADER_MANUFACTURER..HEADER_CGB_FLAG];
+        let manufacturer = if cgb != CgbSupport::None
+                && manufacturer_bytes.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()) {
+            Some(String::from_utf8_lossy(manufacturer_bytes).into_owned())
+        } else {
+            None
+        };
+        let title_end = if manufacturer.is_some() {
+            HEADER_MANUFACTURER
+        } else if cgb != CgbSupport::None {
+            HEADER_CGB_FLAG
+        } else {
+            HEADER_NEW_LICENSEE
+        };
+        let title: String = rom[HEADER_TITLE..title_end].iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| if (0x20..0x7F).contains(&c) { c as char } else { '?' })
+            .collect();
+        let licensee = if rom[HEADER_OLD_LICENSEE] == 0x33 {
+            let code = &rom[HEADER_NEW_LICENSEE..HEADER_NEW_LICENSEE + 2];
+            Licensee::New(String::from_utf8_lossy(code).into_owned())
+        } else {
+            Licensee::Old(rom[HEADER_OLD_LICENSEE])
+        };
+        let rom_size = match rom[HEADER_ROM_SIZE] {
+            code @ 0x00...0x08 => Some((2 * ROM_BANK_SIZE) << code),
+            0x52 => Some(72 * ROM_BANK_SIZE),
+            0x53 => Some(80 * ROM_BANK_SIZE),
+            0x54 => Some(96 * ROM_BANK_SIZE),
+            _ => None,
+        };
+        let header_checksum = rom[HEADER_CHECKSUM];
+        let global_checksum = (rom[HEADER_GLOBAL_CHECKSUM] as u16) << 8
+            | rom[HEADER_GLOBAL_CHECKSUM + 1] as u16;
+        Ok(CartridgeHeader {
+            title: title,
+            manufacturer: manufacturer,
+            cgb: cgb,
+            sgb: rom[HEADER_SGB_FLAG] == 0x03,
+            cart_type: rom[HEADER_CART_TYPE],
+            rom_size: rom_size,
+            ram_size: ram_size(rom[HEADER_RAM_SIZE]),
+            licensee: licensee,
+            version: rom[HEADER_VERSION],
+            header_checksum: header_checksum,
+            global_checksum: global_checksum,
+            header_checksum_ok: compute_header_checksum(rom) == header_checksum,
+            global_checksum_ok: compute_global_checksum(rom) == global_checksum,
+            logo_ok: rom[HEADER_LOGO..HEADER_LOGO + NINTENDO_LOGO.len()] == NINTENDO_LOGO[..],
+        })
+    }
+
+    /// The boot ROM refuses to start a cartridge with a bad header checksum
+    pub fn header_checksum_ok(&self) -> bool {
+        self.header_checksum_ok
+    }
+
+    /// Not checked by hardware, and often wrong in homebrew
+    pub fn global_checksum_ok(&self) -> bool {
+        self.global_checksum_ok
+    }
+
+    /// The boot ROM locks up unless the logo matches
+    pub fn logo_ok(&self) -> bool {
+        self.logo_ok
+    }
+
+    pub fn has_battery(&self) -> bool {
+        save::has_battery(self.cart_type)
+    }
+
+    /// Human readable name of the cartridge type
+    pub fn cart_type_name(&self) -> &'static str {
+        cart_type_name(self.cart_type)
+    }
+
+}
+
+impl fmt::Display for CartridgeHeader {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ok = |x: bool| if x { "ok" } else { "BAD" };
+        try!(writeln!(f, "Title:           {}", self.title));
+        if let Some(ref code) = self.manufacturer {
+            try!(writeln!(f, "Manufacturer:    {}", code));
+        }
+        try!(writeln!(f, "CGB:             {}", match self.cgb {
+            CgbSupport::None => "no",
+            CgbSupport::Compatible => "supported",
+            CgbSupport::Only => "required",
+        }));
+        try!(writeln!(f, "SGB:             {}", if self.sgb { "yes" } else { "no" }));
+        try!(writeln!(f, "Cartridge type:  {:02X}h ({})", self.cart_type, self.cart_type_name()));
+        match self.rom_size {
+            Some(size) => try!(writeln!(f, "ROM size:        {}kB", size / 1024)),
+            None => try!(writeln!(f, "ROM size:        unknown")),
+        }
+        try!(writeln!(f, "RAM size:        {}kB", self.ram_size / 1024));
+        match self.licensee {
+            Licensee::Old(code) => try!(writeln!(f, "Licensee:        {:02X}h", code)),
+            Licensee::New(ref code) => try!(writeln!(f, "Licensee:        {}", code)),
+        }
+        try!(writeln!(f, "Version:         {}", self.version));
+        try!(writeln!(f, "Nintendo logo:   {}", ok(self.logo_ok)));
+        try!(writeln!(f, "Header checksum: {:02X}h {}", self.header_checksum, ok(self.header_checksum_ok)));
+        write!(f, "Global checksum: {:04X}h {}", self.global_checksum, ok(self.global_checksum_ok))
+    }
+
+}
+
+/// Human readable name of a cartridge type code
+pub fn cart_type_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
+}
+
+/// Checksum over 0x0134-0x014C, as verified by the boot ROM
+pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+    rom[HEADER_TITLE..HEADER_CHECKSUM].iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+/// Sum of every ROM byte, except the global checksum itself
+pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+    let sum = rom.iter().fold(0u16, |x, &b| x.wrapping_add(b as u16));
+    sum.wrapping_sub(rom[HEADER_GLOBAL_CHECKSUM] as u16)
+        .wrapping_sub(rom[HEADER_GLOBAL_CHECKSUM + 1] as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 32kB ROM with a valid logo and checksums
+    fn test_rom(title: &[u8], cgb: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[HEADER_LOGO..HEADER_LOGO + 48].copy_from_slice(&NINTENDO_LOGO);
+        rom[HEADER_TITLE..HEADER_TITLE + title.len()].copy_from_slice(title);
+        rom[HEADER_CGB_FLAG] = cgb;
+        rom[HEADER_CART_TYPE] = 0x13;
+        rom[HEADER_ROM_SIZE] = 0x00;
+        rom[HEADER_RAM_SIZE] = 0x03;
+        rom[HEADER_OLD_LICENSEE] = 0x01;
+        rom[HEADER_VERSION] = 0x02;
+        rom[HEADER_CHECKSUM] = compute_header_checksum(&rom);
+        let global = compute_global_checksum(&rom);
+        rom[HEADER_GLOBAL_CHECKSUM] = (global >> 8) as u8;
+        rom[HEADER_GLOBAL_CHECKSUM + 1] = global as u8;
+        rom
+    }
+
+    #[test]
+    fn decode_fields() {
+        let rom = test_rom(b"TETRIS", 0x00);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer, None);
+        assert_eq!(header.cgb, CgbSupport::None);
+        assert!(!header.sgb);
+        assert_eq!(header.cart_type_name(), "MBC3+RAM+BATTERY");
+        assert!(header.has_battery());
+        assert_eq!(header.rom_size, Some(0x8000));
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.licensee, Licensee::Old(0x01));
+        assert_eq!(header.version, 2);
+        assert!(header.logo_ok());
+        assert!(header.header_checksum_ok());
+        assert!(header.global_checksum_ok());
+    }
+
+    #[test]
+    fn cgb_title_and_manufacturer() {
+        let rom = test_rom(b"POKEMON_SLVAAXE", 0x80);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer, Some("AAXE".to_string()));
+        assert_eq!(header.cgb, CgbSupport::Compatible);
+        let rom = test_rom(b"ZELDA DX", 0xC0);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "ZELDA DX");
+        assert_eq!(header.manufacturer, None);
+        assert_eq!(header.cgb, CgbSupport::Only);
+    }
+
+    #[test]
+    fn bad_checksums() {
+        let mut rom = test_rom(b"TEST", 0x00);
+        rom[0x4000] = 0x55;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_ok());
+        assert!(!header.global_checksum_ok());
+        rom[HEADER_VERSION] = 0x03;
+        rom[HEADER_LOGO] = 0x00;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.header_checksum_ok());
+        assert!(!header.logo_ok());
+        assert!(CartridgeHeader::parse(&rom[..0x14F]).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use cart::{Cartridge, ROM_BANK_SIZE, RAM_BANK_SIZE};
+
+/// MBC1 bank controller, supporting up to 2MB of ROM and 32kB of RAM
+///
+/// Multicart (MBC1M) boards wire the upper bank bits one bit lower, so that
+/// the 2-bit register selects between four games of 256kB each.
+pub struct Mbc1 {
+    rom:            Vec<u8>,
+    ram:            Vec<u8>,
+    ram_enabled:    bool,
+    /// 5-bit ROM bank register, 0x2000-0x3FFF
+    rom_bank:       u8,
+    /// 2-bit register, 0x4000-0x5FFF. Upper ROM bank bits, or RAM bank
+    bank2:          u8,
+    /// Banking mode, 0x6000-0x7FFF. Mode 1 also applies bank2 to
+    /// 0x0000-0x3FFF and external RAM.
+    mode:           bool,
+    multicart:      bool,
+}
+
+impl Mbc1 {
+
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Mbc1 {
+        let multicart = is_multicart(&rom);
+        Mbc1 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            bank2: 0,
+            mode: false,
+            multicart: multicart,
+        }
+    }
+
+    pub fn is_multicart(&self) -> bool {
+        self.multicart
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn rom_bank_lo(&self) -> u8 {
+        if self.multicart { self.rom_bank & 0x0F } else { self.rom_bank }
+    }
+
+    fn read_bank(&self, bank: usize, addr: u16) -> u8 {
+        let banks = self.rom.len() / ROM_BANK_SIZE;
+        if banks == 0 {
+            return 0xFF;
+        }
+        let offset = (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom[offset]
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = if self.mode { self.bank2 as usize } else { 0 };
+        let offset = bank * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+}
+
+impl Cartridge for Mbc1 {
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 {
+            if self.mode { self.bank2 << self.bank2_shift() } else { 0 }
+        } else {
+            (self.bank2 << self.bank2_shift()) | self.rom_bank_lo()
+        };
+        self.read_bank(bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = (data & 0x0F) == 0x0A,
+            0x2000...0x3FFF => {
+                // Bank 0 can't be selected here, and maps to bank 1 instead
+                self.rom_bank = data & 0x1F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000...0x5FFF => self.bank2 = data & 0x03,
+            _ => self.mode = (data & 0x01) != 0,
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram_offset(addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool {
+        match self.ram_offset(addr) {
+            Some(offset) => {
+                self.ram[offset] = data;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+}
+
+/// Multicarts are 1MB, and repeat the Nintendo logo at the start of each game
+fn is_multicart(rom: &[u8]) -> bool {
+    if rom.len() != 0x100000 {
+        return false;
+    }
+    let logo = &rom[0x104..0x134];
+    let game2 = 0x10 * ROM_BANK_SIZE;
+    &rom[game2 + 0x104 .. game2 + 0x134] == logo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{Cartridge, ROM_BANK_SIZE};
+
+    /// ROM where the first byte of each bank holds the bank number
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn rom_banking() {
+        let mut cart = Mbc1::new(numbered_rom(128), 0);
+        assert_eq!(cart.read_rom(0x0000), 0);
+        assert_eq!(cart.read_rom(0x4000), 1);
+        cart.write_rom(0x2000, 0x05);
+        assert_eq!(cart.read_rom(0x4000), 5);
+        // Bank 0 maps to 1, as do the other multiples of 0x20
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(cart.read_rom(0x4000), 1);
+        cart.write_rom(0x4000, 0x01);
+        assert_eq!(cart.read_rom(0x4000), 0x21);
+        cart.write_rom(0x2000, 0x03);
+        assert_eq!(cart.read_rom(0x4000), 0x23);
+        // Mode 1 also applies the upper bits to the first bank
+        assert_eq!(cart.read_rom(0x0000), 0);
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_rom(0x0000), 0x20);
+        // Bank numbers wrap at the ROM size
+        let mut small = Mbc1::new(numbered_rom(8), 0);
+        small.write_rom(0x2000, 0x0B);
+        assert_eq!(small.read_rom(0x4000), 3);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut cart = Mbc1::new(numbered_rom(4), 0x8000);
+        // Disabled RAM reads high, and ignores writes
+        assert!(!cart.write_ram(0xA000, 0x12));
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+        cart.write_rom(0x0000, 0x0A);
+        assert!(cart.write_ram(0xA000, 0x12));
+        assert_eq!(cart.read_ram(0xA000), 0x12);
+        // RAM banks only switch in mode 1
+        cart.write_rom(0x4000, 0x02);
+        assert_eq!(cart.read_ram(0xA000), 0x12);
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_ram(0xA000), 0x00);
+        cart.write_ram(0xA000, 0x34);
+        cart.write_rom(0x4000, 0x00);
+        assert_eq!(cart.read_ram(0xA000), 0x12);
+        cart.write_rom(0x0000, 0x00);
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut rom = numbered_rom(64);
+        for game in 0..4 {
+            let base = game * 0x10 * ROM_BANK_SIZE;
+            for i in 0x104..0x134 {
+                rom[base + i] = i as u8;
+            }
+        }
+        let mut cart = Mbc1::new(rom, 0);
+        assert!(cart.is_multicart());
+        cart.write_rom(0x4000, 0x02);
+        cart.write_rom(0x2000, 0x13);
+        // Only 4 bits of the ROM bank register are wired
+        assert_eq!(cart.read_rom(0x4000), 0x23);
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_rom(0x0000), 0x20);
+    }
+}
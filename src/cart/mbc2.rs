@@ -0,0 +1,123 @@
+use cart::{Cartridge, ROM_BANK_SIZE};
+
+/// Size of the MBC2's built-in RAM, in 4-bit cells
+const MBC2_RAM_SIZE: usize = 0x200;
+
+/// MBC2 bank controller, supporting up to 256kB of ROM, with 512x4 bits of
+/// RAM inside the controller itself
+///
+/// Both registers live in 0x0000-0x3FFF, and address bit 8 selects between
+/// them. Only the low nibble of each RAM byte exists, so the upper nibble
+/// reads high, and the 512 cells repeat throughout 0xA000-0xBFFF.
+pub struct Mbc2 {
+    rom:            Vec<u8>,
+    ram:            Vec<u8>,
+    ram_enabled:    bool,
+    /// 4-bit ROM bank register
+    rom_bank:       u8,
+}
+
+impl Mbc2 {
+
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom: rom,
+            ram: vec![0; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+}
+
+impl Cartridge for Mbc2 {
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = self.rom.len() / ROM_BANK_SIZE;
+        if banks == 0 {
+            return 0xFF;
+        }
+        self.rom[(bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))]
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        if addr >= 0x4000 {
+            return;
+        }
+        if (addr & 0x0100) == 0 {
+            self.ram_enabled = (data & 0x0F) == 0x0A;
+        } else {
+            self.rom_bank = data & 0x0F;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        0xF0 | self.ram[addr as usize & (MBC2_RAM_SIZE - 1)]
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool {
+        if self.ram_enabled {
+            self.ram[addr as usize & (MBC2_RAM_SIZE - 1)] = data & 0x0F;
+        }
+        self.ram_enabled
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{Cartridge, ROM_BANK_SIZE};
+
+    #[test]
+    fn register_select() {
+        let mut rom = vec![0; 16 * ROM_BANK_SIZE];
+        for bank in 0..16 {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        let mut cart = Mbc2::new(rom);
+        // Bit 8 clear: RAM enable, whatever the rest of the address
+        cart.write_rom(0x2000, 0x05);
+        assert_eq!(cart.read_rom(0x4000), 1);
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+        cart.write_rom(0x20FF, 0x0A);
+        assert_eq!(cart.read_ram(0xA000), 0xF0);
+        // Bit 8 set: ROM bank, even in the lower half
+        cart.write_rom(0x0100, 0x03);
+        assert_eq!(cart.read_rom(0x4000), 3);
+        cart.write_rom(0x3F00, 0x00);
+        assert_eq!(cart.read_rom(0x4000), 1);
+        cart.write_rom(0x2100, 0x1F);
+        assert_eq!(cart.read_rom(0x4000), 15);
+    }
+
+    #[test]
+    fn nibble_ram() {
+        let mut cart = Mbc2::new(vec![0; 2 * ROM_BANK_SIZE]);
+        assert!(!cart.write_ram(0xA000, 0x05));
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+        cart.write_rom(0x0000, 0x0A);
+        assert!(cart.write_ram(0xA000, 0xA5));
+        assert_eq!(cart.read_ram(0xA000), 0xF5);
+        // 512 cells, echoed through the whole area
+        assert_eq!(cart.read_ram(0xA200), 0xF5);
+        assert_eq!(cart.read_ram(0xBE00), 0xF5);
+        cart.write_ram(0xB1FF, 0x0C);
+        assert_eq!(cart.read_ram(0xA1FF), 0xFC);
+    }
+}
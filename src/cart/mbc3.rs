@@ -0,0 +1,172 @@
+use cart::{Cartridge, ROM_BANK_SIZE, RAM_BANK_SIZE};
+use cart::rtc::Rtc;
+
+/// MBC3 bank controller, supporting up to 2MB of ROM, 32kB of RAM, and an
+/// optional real-time clock
+pub struct Mbc3 {
+    rom:            Vec<u8>,
+    ram:            Vec<u8>,
+    rtc:            Option<Rtc>,
+    /// Enables both RAM and the clock registers
+    ram_enabled:    bool,
+    /// 7-bit ROM bank register, 0x2000-0x3FFF
+    rom_bank:       u8,
+    /// RAM bank 0x00-0x03, or clock register 0x08-0x0C, 0x4000-0x5FFF
+    ram_select:     u8,
+    /// Last value written to the latch register, 0x6000-0x7FFF
+    latch_last:     u8,
+}
+
+impl Mbc3 {
+
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            rtc: if has_rtc { Some(Rtc::new()) } else { None },
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch_last: 0xFF,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() || self.ram_select > 0x03 {
+            return None;
+        }
+        let offset = self.ram_select as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+}
+
+impl Cartridge for Mbc3 {
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank as usize };
+        let banks = self.rom.len() / ROM_BANK_SIZE;
+        if banks == 0 {
+            return 0xFF;
+        }
+        self.rom[(bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1))]
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = (data & 0x0F) == 0x0A,
+            0x2000...0x3FFF => {
+                self.rom_bank = data & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            },
+            0x4000...0x5FFF => self.ram_select = data,
+            _ => {
+                // Writing 0 then 1 latches the clock
+                if self.latch_last == 0x00 && data == 0x01 {
+                    if let Some(ref mut rtc) = self.rtc {
+                        rtc.latch();
+                    }
+                }
+                self.latch_last = data;
+            },
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        if let Some(ref rtc) = self.rtc {
+            if self.ram_select >= 0x08 {
+                return rtc.read(self.ram_select);
+            }
+        }
+        match self.ram_offset(addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        if self.ram_select >= 0x08 {
+            return match self.rtc {
+                Some(ref mut rtc) => {
+                    rtc.write(self.ram_select, data);
+                    true
+                },
+                None => false,
+            };
+        }
+        match self.ram_offset(addr) {
+            Some(offset) => {
+                self.ram[offset] = data;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{Cartridge, ROM_BANK_SIZE};
+    use cart::rtc::RTC_SECONDS;
+    use cpu::GB_FREQUENCY;
+
+    #[test]
+    fn rom_and_ram_banking() {
+        let mut rom = vec![0; 128 * ROM_BANK_SIZE];
+        for bank in 0..128 {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        let mut cart = Mbc3::new(rom, 0x8000, false);
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(cart.read_rom(0x4000), 1);
+        cart.write_rom(0x2000, 0x45);
+        assert_eq!(cart.read_rom(0x4000), 0x45);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, 0x03);
+        cart.write_ram(0xA000, 0x33);
+        cart.write_rom(0x4000, 0x00);
+        assert_eq!(cart.read_ram(0xA000), 0x00);
+        cart.write_rom(0x4000, 0x03);
+        assert_eq!(cart.read_ram(0xA000), 0x33);
+    }
+
+    #[test]
+    fn clock_registers() {
+        let mut cart = Mbc3::new(vec![0; 2 * ROM_BANK_SIZE], 0x2000, true);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, RTC_SECONDS);
+        cart.rtc().unwrap().tick(GB_FREQUENCY * 3);
+        assert_eq!(cart.read_ram(0xA000), 0);
+        // Latch on a 0 to 1 transition only
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_ram(0xA000), 0);
+        cart.write_rom(0x6000, 0x00);
+        cart.write_rom(0x6000, 0x01);
+        assert_eq!(cart.read_ram(0xB123), 3);
+        // Disabling RAM also hides the clock
+        cart.write_rom(0x0000, 0x00);
+        assert_eq!(cart.read_ram(0xA000), 0xFF);
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::VecDeque;
+
+use cart::{Cartridge, CartEvent, ROM_BANK_SIZE, RAM_BANK_SIZE};
+
+/// Most rumble changes kept for the frontend. Games pulse the motor rapidly,
+/// so anything older is dropped if nobody polls.
+const MAX_EVENTS: usize = 64;
+
+/// MBC5 bank controller, supporting up to 8MB of ROM and 128kB of RAM
+///
+/// Rumble cartridges wire bit 3 of the RAM bank register to a motor instead
+/// of the RAM, leaving them with 8 RAM banks.
+pub struct Mbc5 {
+    rom:            Vec<u8>,
+    ram:            Vec<u8>,
+    ram_enabled:    bool,
+    /// 9-bit ROM bank. Low bits at 0x2000-0x2FFF, bit 8 at 0x3000-0x3FFF
+    rom_bank:       u16,
+    /// 4-bit RAM bank register, 0x4000-0x5FFF
+    ram_bank:       u8,
+    has_rumble:     bool,
+    rumble:         bool,
+    /// Rumble changes not yet seen by the frontend, oldest first
+    events:         VecDeque<CartEvent>,
+}
+
+impl Mbc5 {
+
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom: rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble: has_rumble,
+            rumble: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// True while the rumble motor is running
+    pub fn is_rumbling(&self) -> bool {
+        self.rumble
+    }
+
+    fn read_bank(&self, bank: usize, addr: u16) -> u8 {
+        let banks = self.rom.len() / ROM_BANK_SIZE;
+        if banks == 0 {
+            return 0xFF;
+        }
+        let offset = (bank % banks) * ROM_BANK_SIZE + (addr as usize & (ROM_BANK_SIZE - 1));
+        self.rom[offset]
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = if self.has_rumble { self.ram_bank & 0x07 } else { self.ram_bank };
+        let offset = bank as usize * RAM_BANK_SIZE + (addr - 0xA000) as usize;
+        Some(offset % self.ram.len())
+    }
+
+}
+
+impl Cartridge for Mbc5 {
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        self.read_bank(bank as usize, addr)
+    }
+
+    fn write_rom(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram_enabled = data == 0x0A,
+            // Unlike earlier MBCs, bank 0 can be mapped here
+            0x2000...0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000...0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 0x01) << 8),
+            0x4000...0x5FFF => {
+                self.ram_bank = data & 0x0F;
+                if self.has_rumble {
+                    let rumble = (data & 0x08) != 0;
+                    if rumble != self.rumble {
+                        self.rumble = rumble;
+                        if self.events.len() >= MAX_EVENTS {
+                            self.events.pop_front();
+                        }
+                        self.events.push_back(CartEvent::Rumble(rumble));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram_offset(addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool {
+        match self.ram_offset(addr) {
+            Some(offset) => {
+                self.ram[offset] = data;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn poll_event(&mut self) -> Option<CartEvent> {
+        self.events.pop_front()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{Cartridge, CartEvent, ROM_BANK_SIZE};
+
+    /// ROM where the first two bytes of each bank hold the bank number
+    fn numbered_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    fn bank_at(cart: &Mbc5, addr: u16) -> usize {
+        cart.read_rom(addr) as usize | (cart.read_rom(addr + 1) as usize) << 8
+    }
+
+    #[test]
+    fn rom_banking() {
+        let mut cart = Mbc5::new(numbered_rom(512), 0, false);
+        assert_eq!(bank_at(&cart, 0x4000), 1);
+        cart.write_rom(0x2000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 0);
+        cart.write_rom(0x2000, 0xAB);
+        cart.write_rom(0x3000, 0x01);
+        assert_eq!(bank_at(&cart, 0x4000), 0x1AB);
+        assert_eq!(bank_at(&cart, 0x0000), 0);
+        cart.write_rom(0x3000, 0x00);
+        assert_eq!(bank_at(&cart, 0x4000), 0xAB);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut cart = Mbc5::new(numbered_rom(2), 0x20000, false);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, 0x0F);
+        cart.write_ram(0xA000, 0x0F);
+        cart.write_rom(0x4000, 0x07);
+        cart.write_ram(0xA000, 0x07);
+        assert_eq!(cart.read_ram(0xA000), 0x07);
+        cart.write_rom(0x4000, 0x0F);
+        assert_eq!(cart.read_ram(0xA000), 0x0F);
+        assert_eq!(cart.poll_event(), None);
+    }
+
+    #[test]
+    fn rumble() {
+        let mut cart = Mbc5::new(numbered_rom(2), 0x10000, true);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_rom(0x4000, 0x03);
+        cart.write_ram(0xA000, 0x33);
+        assert_eq!(cart.poll_event(), None);
+        // The motor bit doesn't select RAM
+        cart.write_rom(0x4000, 0x0B);
+        assert!(cart.is_rumbling());
+        assert_eq!(cart.read_ram(0xA000), 0x33);
+        assert_eq!(cart.poll_event(), Some(CartEvent::Rumble(true)));
+        assert_eq!(cart.poll_event(), None);
+        // Only changes are reported
+        cart.write_rom(0x4000, 0x0A);
+        assert_eq!(cart.poll_event(), None);
+        cart.write_rom(0x4000, 0x02);
+        assert_eq!(cart.poll_event(), Some(CartEvent::Rumble(false)));
+    }
+
+    #[test]
+    fn rumble_events_queue() {
+        let mut cart = Mbc5::new(numbered_rom(2), 0x10000, true);
+        // A short pulse between polls still reports both edges, in order
+        cart.write_rom(0x4000, 0x08);
+        cart.write_rom(0x4000, 0x00);
+        assert_eq!(cart.poll_event(), Some(CartEvent::Rumble(true)));
+        assert_eq!(cart.poll_event(), Some(CartEvent::Rumble(false)));
+        assert_eq!(cart.poll_event(), None);
+        // Without polling, only the latest changes are kept
+        for _ in 0..MAX_EVENTS {
+            cart.write_rom(0x4000, 0x08);
+            cart.write_rom(0x4000, 0x00);
+        }
+        let events: Vec<CartEvent> = ::std::iter::from_fn(|| cart.poll_event()).collect();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert_eq!(events.last(), Some(&CartEvent::Rumble(false)));
+    }
+}
@@ -0,0 +1,165 @@
+//! Cartridges, and the memory bank controllers (MBCs) inside them
+//!
+//! A cartridge is mapped into the address space at 0x0000-0x7FFF (ROM) and
+//! 0xA000-0xBFFF (external RAM). Writes to the ROM area never change ROM, and
+//! instead program the bank controller registers.
+
+use std::io;
+
+pub mod header;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+pub mod rtc;
+pub mod save;
+
+pub use self::header::CartridgeHeader;
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+pub use self::rtc::Rtc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Something a cartridge did that the frontend may want to show
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CartEvent {
+    /// The rumble motor was switched on (true) or off (false)
+    Rumble(bool),
+}
+
+pub trait Cartridge: Send {
+
+    /// Read from the ROM area, 0x0000-0x7FFF
+    fn read_rom(&self, addr: u16) -> u8;
+
+    /// Write to the bank controller registers, mapped over 0x0000-0x7FFF
+    fn write_rom(&mut self, addr: u16, data: u8);
+
+    /// Read from external RAM, 0xA000-0xBFFF
+    fn read_ram(&self, addr: u16) -> u8;
+
+    /// Write to external RAM, 0xA000-0xBFFF. Returns false if the write was
+    /// ignored, because the RAM is disabled or not there.
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool;
+
+    /// Contents of external RAM, across all banks
+    fn ram(&self) -> &[u8];
+
+    fn ram_mut(&mut self) -> &mut [u8];
+
+    /// The cartridge's real-time clock, if it has one
+    fn rtc(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    /// Take the oldest event not yet polled, if any
+    fn poll_event(&mut self) -> Option<CartEvent> {
+        None
+    }
+
+}
+
+/// Cartridge without a bank controller: 32kB of ROM, and up to 8kB of RAM
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom: rom,
+            ram: vec![0; ram_size],
+        }
+    }
+
+    /// An empty cartridge slot, where every read floats high
+    pub fn empty() -> RomOnly {
+        RomOnly::new(Vec::new(), 0)
+    }
+
+}
+
+impl Cartridge for RomOnly {
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        match self.rom.get(addr as usize) {
+            Some(&x) => x,
+            None => 0xFF,
+        }
+    }
+
+    fn write_rom(&mut self, _addr: u16, _data: u8) {
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram.get((addr - 0xA000) as usize) {
+            Some(&x) => x,
+            None => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, data: u8) -> bool {
+        match self.ram.get_mut((addr - 0xA000) as usize) {
+            Some(x) => {
+                *x = data;
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+}
+
+/// Size in bytes of external RAM, from the RAM size code in the header
+pub fn ram_size(code: u8) -> usize {
+    match code {
+        0x01 => 0x800,
+        0x02 => RAM_BANK_SIZE,
+        0x03 => RAM_BANK_SIZE * 4,
+        0x04 => RAM_BANK_SIZE * 16,
+        0x05 => RAM_BANK_SIZE * 8,
+        _ => 0,
+    }
+}
+
+/// Build a cartridge from a ROM image and its parsed header, choosing the bank
+/// controller named by the cartridge type
+pub fn load_cartridge(rom: Vec<u8>, header: &CartridgeHeader) -> io::Result<Box<dyn Cartridge>> {
+    let ram_size = header.ram_size;
+    let cart: Box<dyn Cartridge> = match header.cart_type {
+        // ROM only, with optional RAM
+        0x00 | 0x08 | 0x09 => Box::new(RomOnly::new(rom, ram_size)),
+        // MBC1, with optional RAM
+        0x01 | 0x02 | 0x03 => Box::new(Mbc1::new(rom, ram_size)),
+        // MBC2, with built-in RAM
+        0x05 | 0x06 => Box::new(Mbc2::new(rom)),
+        // MBC3, with clock
+        0x0F | 0x10 => Box::new(Mbc3::new(rom, ram_size, true)),
+        // MBC3, with optional RAM
+        0x11 | 0x12 | 0x13 => Box::new(Mbc3::new(rom, ram_size, false)),
+        // MBC5, with optional RAM
+        0x19 | 0x1A | 0x1B => Box::new(Mbc5::new(rom, ram_size, false)),
+        // MBC5 with rumble motor, with optional RAM
+        0x1C | 0x1D | 0x1E => Box::new(Mbc5::new(rom, ram_size, true)),
+        _ => {
+            let msg = format!("Unsupported cartridge type {:02X}h ({})",
+                              header.cart_type, header.cart_type_name());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        },
+    };
+    Ok(cart)
+}
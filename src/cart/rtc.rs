@@ -0,0 +1,383 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cpu::GB_FREQUENCY;
+
+/// Real-time clock found in MBC3 cartridges
+///
+/// By default the clock is driven by emulated cycles through `tick`, so runs
+/// stay deterministic. With host sync on, it instead follows the host's wall
+/// clock, as a real cartridge does while the console is switched off.
+pub struct Rtc {
+    seconds:        u8,
+    minutes:        u8,
+    hours:          u8,
+    days:           u16,
+    halt:           bool,
+    carry:          bool,
+    /// Register values captured by the last latch
+    latched:        [u8; 5],
+    /// Cycles counted towards the next second
+    cycles:         u32,
+    /// Host time of the last sync, in seconds since the epoch
+    host_time:      Option<u64>,
+}
+
+pub const RTC_SECONDS:  u8 = 0x08;
+pub const RTC_MINUTES:  u8 = 0x09;
+pub const RTC_HOURS:    u8 = 0x0A;
+pub const RTC_DAYS_LO:  u8 = 0x0B;
+pub const RTC_DAYS_HI:  u8 = 0x0C;
+
+/// Size of the clock footer appended to save files, as written by BGB and
+/// VBA. Some older files use a 32-bit timestamp, and are 4 bytes shorter.
+pub const RTC_FOOTER_SIZE: usize = 48;
+
+impl Rtc {
+
+    pub fn new() -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            cycles: 0,
+            host_time: None,
+        }
+    }
+
+    /// Follow the host clock instead of emulated time
+    pub fn set_host_sync(&mut self, on: bool) {
+        self.host_time = if on { Some(host_seconds()) } else { None };
+    }
+
+    pub fn is_host_sync(&self) -> bool {
+        self.host_time.is_some()
+    }
+
+    /// Advance emulated time
+    pub fn tick(&mut self, cycles: u32) {
+        if self.halt || self.host_time.is_some() {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= GB_FREQUENCY {
+            self.cycles -= GB_FREQUENCY;
+            self.tick_second();
+        }
+    }
+
+    /// Catch up with the host clock, if host sync is on
+    pub fn sync_host(&mut self) {
+        if let Some(last) = self.host_time {
+            let now = host_seconds();
+            if now > last {
+                if !self.halt {
+                    self.advance(now - last);
+                }
+                self.host_time = Some(now);
+            }
+        }
+    }
+
+    /// Copy the live registers into the readable latch
+    pub fn latch(&mut self) {
+        self.sync_host();
+        self.latched = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            (self.days & 0xFF) as u8,
+            self.read_days_hi(),
+        ];
+    }
+
+    /// Read a latched register, selected by 0x08-0x0C
+    pub fn read(&self, reg: u8) -> u8 {
+        match reg {
+            RTC_SECONDS...RTC_DAYS_HI => self.latched[(reg - RTC_SECONDS) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    /// Write a live register, selected by 0x08-0x0C
+    pub fn write(&mut self, reg: u8, data: u8) {
+        self.sync_host();
+        match reg {
+            RTC_SECONDS => {
+                self.seconds = data & 0x3F;
+                // Writing the seconds restarts the current second
+                self.cycles = 0;
+            },
+            RTC_MINUTES => self.minutes = data & 0x3F,
+            RTC_HOURS => self.hours = data & 0x1F,
+            RTC_DAYS_LO => self.days = (self.days & 0x100) | data as u16,
+            RTC_DAYS_HI => {
+                self.days = (self.days & 0xFF) | ((data as u16 & 0x01) << 8);
+                self.halt = (data & 0x40) != 0;
+                self.carry = (data & 0x80) != 0;
+            },
+            _ => return,
+        }
+        // Games commonly read back what they wrote without latching again
+        self.latched[(reg - RTC_SECONDS) as usize] = match reg {
+            RTC_DAYS_HI => self.read_days_hi(),
+            RTC_DAYS_LO => (self.days & 0xFF) as u8,
+            RTC_HOURS => self.hours,
+            RTC_MINUTES => self.minutes,
+            _ => self.seconds,
+        };
+    }
+
+    /// Serialize the clock for a save file, stamped with host time `now`
+    ///
+    /// The footer holds the live registers, then the latched registers, each
+    /// as a little endian 32-bit word, then a 64-bit UNIX timestamp.
+    pub fn save_footer(&self, now: u64) -> Vec<u8> {
+        let live = [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            (self.days & 0xFF) as u8,
+            self.read_days_hi(),
+        ];
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for &reg in live.iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&[reg, 0, 0, 0]);
+        }
+        for i in 0..8 {
+            footer.push((now >> (i * 8)) as u8);
+        }
+        footer
+    }
+
+    /// Restore the clock from a save file footer. With host sync on, the time
+    /// that passed between the timestamp and `now` is counted too; otherwise
+    /// the clock resumes exactly where it was saved. Returns false if the
+    /// footer isn't recognised.
+    pub fn load_footer(&mut self, footer: &[u8], now: u64) -> bool {
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE - 4 {
+            return false;
+        }
+        let word = |i: usize| footer[i * 4] as u64
+            | (footer[i * 4 + 1] as u64) << 8
+            | (footer[i * 4 + 2] as u64) << 16
+            | (footer[i * 4 + 3] as u64) << 24;
+        self.seconds = word(0) as u8 & 0x3F;
+        self.minutes = word(1) as u8 & 0x3F;
+        self.hours = word(2) as u8 & 0x1F;
+        let days_hi = word(4) as u8;
+        self.days = (word(3) as u16 & 0xFF) | ((days_hi as u16 & 0x01) << 8);
+        self.halt = (days_hi & 0x40) != 0;
+        self.carry = (days_hi & 0x80) != 0;
+        for i in 0..5 {
+            self.latched[i] = word(5 + i) as u8;
+        }
+        let mut saved = word(10);
+        if footer.len() == RTC_FOOTER_SIZE {
+            saved |= word(11) << 32;
+        }
+        self.cycles = 0;
+        if self.host_time.is_some() {
+            if !self.halt && now > saved {
+                self.advance(now - saved);
+            }
+            self.host_time = Some(now);
+        }
+        true
+    }
+
+    fn read_days_hi(&self) -> u8 {
+        let mut x = ((self.days >> 8) & 0x01) as u8;
+        if self.halt {
+            x |= 0x40;
+        }
+        if self.carry {
+            x |= 0x80;
+        }
+        x
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    /// Count one second. Out of range values keep counting until the
+    /// register overflows, without carrying into the next one.
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    /// Count many seconds at once
+    pub fn advance(&mut self, secs: u64) {
+        let mut secs = secs;
+        while secs > 0 && !self.in_range() {
+            self.tick_second();
+            secs -= 1;
+        }
+        if secs == 0 {
+            return;
+        }
+        let mut total = self.days as u64;
+        total = total * 24 + self.hours as u64;
+        total = total * 60 + self.minutes as u64;
+        total = total * 60 + self.seconds as u64 + secs;
+        self.seconds = (total % 60) as u8;
+        total /= 60;
+        self.minutes = (total % 60) as u8;
+        total /= 60;
+        self.hours = (total % 24) as u8;
+        total /= 24;
+        if total > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (total & 0x1FF) as u16;
+    }
+
+}
+
+/// Host time in seconds since the UNIX epoch
+pub fn host_seconds() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::GB_FREQUENCY;
+
+    #[test]
+    fn counts_emulated_time() {
+        let mut rtc = Rtc::new();
+        rtc.tick(GB_FREQUENCY - 1);
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        rtc.tick(1);
+        // Not visible until latched
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+    }
+
+    #[test]
+    fn rollover() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_SECONDS, 59);
+        rtc.write(RTC_MINUTES, 59);
+        rtc.write(RTC_HOURS, 23);
+        rtc.write(RTC_DAYS_LO, 0xFF);
+        rtc.write(RTC_DAYS_HI, 0x01);
+        rtc.tick(GB_FREQUENCY);
+        rtc.latch();
+        let regs: Vec<u8> = (RTC_SECONDS..RTC_DAYS_HI + 1).map(|r| rtc.read(r)).collect();
+        assert_eq!(regs, vec![0, 0, 0, 0, 0x80]);
+        // Carry stays set until cleared
+        rtc.tick(GB_FREQUENCY);
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_DAYS_HI), 0x80);
+        rtc.write(RTC_DAYS_HI, 0x00);
+        assert_eq!(rtc.read(RTC_DAYS_HI), 0x00);
+    }
+
+    #[test]
+    fn out_of_range_values() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_SECONDS, 62);
+        rtc.advance(3);
+        rtc.latch();
+        // 62, 63, then wraps to 0 without touching minutes
+        assert_eq!(rtc.read(RTC_SECONDS), 1);
+        assert_eq!(rtc.read(RTC_MINUTES), 0);
+    }
+
+    #[test]
+    fn halt_stops_time() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_DAYS_HI, 0x40);
+        rtc.tick(GB_FREQUENCY * 5);
+        rtc.latch();
+        assert_eq!(rtc.read(RTC_SECONDS), 0);
+        assert_eq!(rtc.read(RTC_DAYS_HI), 0x40);
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_MINUTES, 30);
+        rtc.write(RTC_DAYS_LO, 0x20);
+        rtc.write(RTC_DAYS_HI, 0x81);
+        let footer = rtc.save_footer(1000);
+        assert_eq!(footer.len(), RTC_FOOTER_SIZE);
+        assert_eq!(&footer[4..8], &[30, 0, 0, 0]);
+        assert_eq!(&footer[16..20], &[0x81, 0, 0, 0]);
+        assert_eq!(&footer[40..48], &[0xE8, 0x03, 0, 0, 0, 0, 0, 0]);
+        // Time passes while the emulator is closed, when following the host
+        let mut loaded = Rtc::new();
+        loaded.set_host_sync(true);
+        assert!(loaded.load_footer(&footer, 1000 + 3600 + 5));
+        assert_eq!(loaded.seconds, 5);
+        assert_eq!(loaded.minutes, 30);
+        assert_eq!(loaded.hours, 1);
+        assert_eq!(loaded.days, 0x120);
+        assert!(loaded.carry);
+        // 32-bit timestamps are accepted too
+        assert!(Rtc::new().load_footer(&footer[..44], 1000));
+        assert!(!Rtc::new().load_footer(&footer[..40], 1000));
+    }
+
+    #[test]
+    fn load_without_host_sync() {
+        let mut rtc = Rtc::new();
+        rtc.write(RTC_SECONDS, 12);
+        rtc.write(RTC_HOURS, 3);
+        rtc.latch();
+        let footer = rtc.save_footer(1000);
+        // Emulated time only, so the gap since saving doesn't count
+        for &now in [1000, 1000 + 86400, 50].iter() {
+            let mut loaded = Rtc::new();
+            assert!(loaded.load_footer(&footer, now));
+            loaded.latch();
+            assert_eq!(loaded.latched, rtc.latched);
+        }
+    }
+
+    #[test]
+    fn advance_matches_ticks() {
+        let mut a = Rtc::new();
+        let mut b = Rtc::new();
+        a.write(RTC_HOURS, 22);
+        b.write(RTC_HOURS, 22);
+        let secs = 3 * 86400 + 7261;
+        a.advance(secs);
+        for _ in 0..secs {
+            b.tick_second();
+        }
+        a.latch();
+        b.latch();
+        assert_eq!(a.latched, b.latched);
+    }
+}
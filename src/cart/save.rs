@@ -0,0 +1,88 @@
+//! Battery backed save files
+//!
+//! A save file holds the contents of external RAM, followed by the clock
+//! footer for cartridges with an RTC. This matches the `.sav` files written by
+//! BGB and VBA, so saves can be moved between emulators.
+
+use std::io;
+use std::io::{Read, Write};
+
+use cart::Cartridge;
+
+/// Whether a cartridge type from the header keeps its RAM (or clock) powered
+/// by a battery
+pub fn has_battery(cart_type: u8) -> bool {
+    match cart_type {
+        0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF => true,
+        _ => false,
+    }
+}
+
+/// Write external RAM, and the clock if there is one, stamped with host time
+/// `now`
+pub fn write_save<W: Write>(cart: &mut dyn Cartridge, out: &mut W, now: u64) -> io::Result<()> {
+    try!(out.write_all(cart.ram()));
+    if let Some(rtc) = cart.rtc() {
+        try!(out.write_all(&rtc.save_footer(now)));
+    }
+    out.flush()
+}
+
+/// Restore external RAM, and the clock if the file has a footer for it
+///
+/// A file shorter than the cartridge's RAM only fills the start of RAM, and
+/// anything after RAM that isn't a clock footer is ignored.
+pub fn read_save<R: Read>(cart: &mut dyn Cartridge, input: &mut R, now: u64) -> io::Result<()> {
+    let mut data = Vec::new();
+    try!(input.read_to_end(&mut data));
+    let ram_len = {
+        let ram = cart.ram_mut();
+        let len = ::std::cmp::min(ram.len(), data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        len
+    };
+    if let Some(rtc) = cart.rtc() {
+        rtc.load_footer(&data[ram_len..], now);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cart::{Cartridge, Mbc3, ROM_BANK_SIZE};
+    use cart::rtc::{RTC_FOOTER_SIZE, RTC_HOURS, RTC_MINUTES};
+
+    #[test]
+    fn ram_and_clock() {
+        let mut cart = Mbc3::new(vec![0; 2 * ROM_BANK_SIZE], 0x8000, true);
+        cart.write_rom(0x0000, 0x0A);
+        cart.write_ram(0xA123, 0x45);
+        cart.write_rom(0x4000, RTC_HOURS);
+        cart.write_ram(0xA000, 5);
+        let mut file = Vec::new();
+        write_save(&mut cart, &mut file, 500).unwrap();
+        assert_eq!(file.len(), 0x8000 + RTC_FOOTER_SIZE);
+        assert_eq!(file[0x123], 0x45);
+
+        let mut loaded = Mbc3::new(vec![0; 2 * ROM_BANK_SIZE], 0x8000, true);
+        read_save(&mut loaded, &mut &file[..], 500 + 120).unwrap();
+        loaded.write_rom(0x0000, 0x0A);
+        loaded.write_rom(0x6000, 0x00);
+        loaded.write_rom(0x6000, 0x01);
+        assert_eq!(loaded.read_ram(0xA123), 0x45);
+        loaded.write_rom(0x4000, RTC_HOURS);
+        assert_eq!(loaded.read_ram(0xA000), 5);
+        // Without host sync the clock doesn't count the time since saving
+        loaded.write_rom(0x4000, RTC_MINUTES);
+        assert_eq!(loaded.read_ram(0xA000), 0);
+    }
+
+    #[test]
+    fn battery_types() {
+        assert!(has_battery(0x03));
+        assert!(has_battery(0x10));
+        assert!(!has_battery(0x01));
+        assert!(!has_battery(0x1C));
+    }
+}
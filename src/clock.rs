@@ -0,0 +1,132 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// Hardware events driven by the emulated clock
+///
+/// Events due on the same cycle are dispatched in declaration order.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum IntType {
+    Lcd,
+    IoTimer,
+}
+
+struct ClockInt {
+    pub int_target: u64,
+    pub int_type: IntType,
+}
+
+impl PartialEq for ClockInt {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.int_target == other.int_target && self.int_type == other.int_type
+    }
+}
+
+impl Eq for ClockInt {}
+
+impl PartialOrd for ClockInt {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ClockInt {
+    /// Reversed, so the earliest event sits at the top of the heap
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.int_target, other.int_type).cmp(&(self.int_target, self.int_type))
+    }
+}
+
+/// Event scheduler keyed on the emulated cycle counter
+///
+/// Nothing here looks at the wall clock, so a run is fully determined by the
+/// program being executed. Pacing against real time is left to frontends.
+pub struct Clock {
+    int_heap:   BinaryHeap<ClockInt>,
+}
+
+impl Clock {
+
+    pub fn new() -> Clock {
+        Clock {
+            int_heap: BinaryHeap::new(),
+        }
+    }
+
+    /// Schedule an event to fire once the cycle counter reaches `target`
+    pub fn set_interrupt(&mut self, itype: IntType, target: u64) {
+        let int = ClockInt {
+            int_type: itype,
+            int_target: target,
+        };
+        self.int_heap.push(int);
+    }
+
+    /// Drop any scheduled events of the given type
+    pub fn cancel_interrupt(&mut self, itype: IntType) {
+        let ints: Vec<ClockInt> = self.int_heap.drain().filter(|int| int.int_type != itype).collect();
+        self.int_heap.extend(ints);
+    }
+
+    /// Pop the earliest event due at or before cycle `now`, along with the
+    /// cycle it was scheduled for
+    pub fn next_interrupt(&mut self, now: u64) -> Option<(IntType, u64)> {
+        let due = match self.int_heap.peek() {
+            Some(int) => int.int_target <= now,
+            None => false,
+        };
+        if due {
+            self.int_heap.pop().map(|int| (int.int_type, int.int_target))
+        } else {
+            None
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_cycle_order() {
+        let mut clock = Clock::new();
+        clock.set_interrupt(IntType::IoTimer, 100);
+        clock.set_interrupt(IntType::Lcd, 100);
+        clock.set_interrupt(IntType::IoTimer, 50);
+        assert_eq!(clock.next_interrupt(100), Some((IntType::IoTimer, 50)));
+        // Ties go by declaration order, whatever order they were set in
+        assert_eq!(clock.next_interrupt(100), Some((IntType::Lcd, 100)));
+        assert_eq!(clock.next_interrupt(100), Some((IntType::IoTimer, 100)));
+        assert_eq!(clock.next_interrupt(100), None);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut clock = Clock::new();
+        clock.set_interrupt(IntType::Lcd, 10);
+        clock.set_interrupt(IntType::IoTimer, 20);
+        clock.set_interrupt(IntType::Lcd, 30);
+        clock.cancel_interrupt(IntType::Lcd);
+        assert_eq!(clock.next_interrupt(100), Some((IntType::IoTimer, 20)));
+        assert_eq!(clock.next_interrupt(100), None);
+        // Cancelling with nothing scheduled is harmless
+        clock.cancel_interrupt(IntType::IoTimer);
+        assert_eq!(clock.next_interrupt(100), None);
+    }
+
+    #[test]
+    fn past_target() {
+        let mut clock = Clock::new();
+        clock.set_interrupt(IntType::Lcd, 500);
+        assert_eq!(clock.next_interrupt(499), None);
+        // An event set for a cycle already gone fires at once, and keeps the
+        // cycle it was meant for
+        clock.set_interrupt(IntType::IoTimer, 100);
+        assert_eq!(clock.next_interrupt(499), Some((IntType::IoTimer, 100)));
+        assert_eq!(clock.next_interrupt(499), None);
+        assert_eq!(clock.next_interrupt(1000), Some((IntType::Lcd, 500)));
+    }
+}
@@ -10,7 +10,8 @@ use std::num::Wrapping;
 pub const GB_FREQUENCY: u32 = 4194304;
 
 pub const TIMER_BASE_FREQUENCY: u64 = 262144;
-pub const TIMER_BASE_PERIOD_NS: u64 = 1_000_000_000 / TIMER_BASE_FREQUENCY;
+/// Period of the base timer clock, in CPU cycles
+pub const TIMER_BASE_PERIOD: u64 = GB_FREQUENCY as u64 / TIMER_BASE_FREQUENCY;
 
 #[derive(Copy, Clone)]
 pub enum CpuState {
@@ -19,16 +20,59 @@ pub enum CpuState {
     Stopped, // No instructions are run, reset on user input
 }
 
-#[derive(Copy, Clone)]
+/// Interrupt master enable (IME)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImeState {
+    Disabled,
+    Pending, // Set by EI, takes effect after the next instruction
+    Enabled,
+}
+
+/// Cycles taken to dispatch an interrupt to its handler
+pub const INTERRUPT_CYCLES: u32 = 20;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum CpuInterrupt {
     Vblank,
-    Lcdc,
+    LcdStat,
     TimerOverflow,
     SerialIoComplete,
-    TransitionP10,
-    TransitionP11,
-    TransitionP12,
-    TransitionP13,
+    Joypad, // High to low transition on any of P10-P13
+}
+
+/// All interrupts, highest priority first
+pub const INTERRUPT_PRIORITY: [CpuInterrupt; 5] = [
+    CpuInterrupt::Vblank,
+    CpuInterrupt::LcdStat,
+    CpuInterrupt::TimerOverflow,
+    CpuInterrupt::SerialIoComplete,
+    CpuInterrupt::Joypad,
+];
+
+impl CpuInterrupt {
+
+    /// Bit of this interrupt in the IF and IE registers
+    pub fn mask(&self) -> u8 {
+        match *self {
+            CpuInterrupt::Vblank => 0x01,
+            CpuInterrupt::LcdStat => 0x02,
+            CpuInterrupt::TimerOverflow => 0x04,
+            CpuInterrupt::SerialIoComplete => 0x08,
+            CpuInterrupt::Joypad => 0x10,
+        }
+    }
+
+    /// Address of the interrupt handler
+    pub fn vector(&self) -> u16 {
+        match *self {
+            CpuInterrupt::Vblank => 0x0040,
+            CpuInterrupt::LcdStat => 0x0048,
+            CpuInterrupt::TimerOverflow => 0x0050,
+            CpuInterrupt::SerialIoComplete => 0x0058,
+            CpuInterrupt::Joypad => 0x0060,
+        }
+    }
+
 }
 
 pub struct Cpu {
@@ -37,7 +81,8 @@ pub struct Cpu {
     freq: u32,
     clock: u64,
     state: CpuState,
-    intlevel: bool,
+    ime: ImeState,
+    halt_bug: bool,
     io_timer: u32,
 }
 
@@ -54,7 +99,8 @@ impl Cpu {
             freq: freq,
             clock: 0,
             state: CpuState::Running,
-            intlevel: true,
+            ime: ImeState::Enabled,
+            halt_bug: false,
             io_timer: 0,
         }
     }
@@ -63,6 +109,11 @@ impl Cpu {
         &mut self.ram
     }
 
+    /// Number of cycles executed since power on
+    pub fn get_clock(&self) -> u64 {
+        self.clock
+    }
+
     pub fn is_stopped(&self) -> bool {
         if let CpuState::Stopped = self.state {
             true
@@ -91,7 +142,7 @@ impl Cpu {
                 // Reset on overflow, and interrupt
                 let tma = self.ram[mem::IOREG_TMA];
                 self.ram.sys_write(mem::IOREG_TIMA, tma);
-                self.interrupt(CpuInterrupt::TimerOverflow);
+                self.request_interrupt(CpuInterrupt::TimerOverflow);
             } else {
                 // Or increment
                 self.ram.sys_write(mem::IOREG_TIMA, time as u8);
@@ -99,45 +150,81 @@ impl Cpu {
         }
     }
 
-    pub fn interrupt(&mut self, int: CpuInterrupt) {
-        let ie_flag = self.ram[mem::IOREG_IE];
-        let (ie_mask, int_addr) = match int {
-            CpuInterrupt::Vblank => {
-                self.ram.write(mem::IOREG_IF, 0x01);
-                (0x01, 0x0040)
-            },
-            CpuInterrupt::TimerOverflow => {
-                self.ram.write(mem::IOREG_IF, 0x04);
-                (0x04, 0x0050)
+    /// Request an interrupt, by latching its bit in the IF register. It is
+    /// serviced at the next instruction boundary, if enabled.
+    pub fn request_interrupt(&mut self, int: CpuInterrupt) {
+        self.ram.request_interrupt(int);
+    }
+
+    /// Interrupts which are both requested and enabled
+    pub fn pending_interrupts(&self) -> u8 {
+        self.ram[mem::IOREG_IE] & self.ram[mem::IOREG_IF] & 0x1F
+    }
+
+    /// Jump to the handler of the highest priority pending interrupt, if
+    /// interrupts are enabled. Returns the cycles taken, or 0 if nothing was
+    /// serviced.
+    fn service_interrupt(&mut self) -> u32 {
+        if self.ime != ImeState::Enabled {
+            return 0;
+        }
+        let pending = self.pending_interrupts();
+        for int in INTERRUPT_PRIORITY.iter() {
+            if pending & int.mask() == 0 {
+                continue;
+            }
+            let flags = self.ram[mem::IOREG_IF] & !int.mask();
+            self.ram.sys_write(mem::IOREG_IF, flags);
+            self.ime = ImeState::Disabled;
+            let mut pc = self.reg.set_pc(int.vector());
+            if self.halt_bug {
+                // After EI; HALT the handler returns to the HALT itself
+                self.halt_bug = false;
+                pc -= 1;
             }
-            _ => {
-                println!("Interrupt not implemented");
-                (0x00, 0x0000)
-            },
-        };
-        if self.intlevel && (ie_flag & ie_mask) != 0 {
-            self.intlevel = false;
-            let pc = self.reg.set_pc(int_addr);
             let sp = self.reg.read_u16(Register::SP) - 2;
             self.ram.write_u16(sp, pc);
             self.reg.write_u16(Register::SP, sp);
-            if let CpuState::Halted = self.state {
-                self.state = CpuState::Running;
-            }
+            self.clock += INTERRUPT_CYCLES as u64;
+            return INTERRUPT_CYCLES;
         }
+        0
     }
 
     pub fn do_instr(&mut self) -> u32 {
+        // Any pending interrupt ends a HALT, even with interrupts disabled.
+        // The handler is only called if interrupts are enabled, otherwise
+        // execution carries on after the HALT.
+        if let CpuState::Halted = self.state {
+            if self.pending_interrupts() != 0 {
+                self.state = CpuState::Running;
+            }
+        }
+        // STOP lasts until a button on a selected row is pressed
+        if let CpuState::Stopped = self.state {
+            if (self.ram[mem::IOREG_P1] & 0x0F) != 0x0F {
+                self.state = CpuState::Running;
+            }
+        }
         match self.state {
             CpuState::Running => (),
             CpuState::Halted | CpuState::Stopped => {
-                if !self.intlevel {
-                    println!("Warning: CPU stopped/halted and interrupts are disabled!");
-                }
-                return 0;
+                // Hardware keeps running while the CPU idles
+                self.clock += 4;
+                return 4;
             }
         }
-        let instr = Instr::parse(&mut self.reg, &self.ram);
+        let int_cycles = self.service_interrupt();
+        if int_cycles != 0 {
+            return int_cycles;
+        }
+        let enable_ime = self.ime == ImeState::Pending;
+        let instr = if self.halt_bug {
+            self.halt_bug = false;
+            Instr::parse_halt_bug(&mut self.reg, &self.ram)
+        } else {
+            Instr::parse(&mut self.reg, &self.ram)
+        };
         match instr.opcode() {
             // 8-bit immediate loads
             0x06 => self.reg.write(Register::B, instr.param(0)),
@@ -1265,9 +1352,7 @@ impl Cpu {
                 }
             },
             // DAA instruction: See Z80 reference for behavior
-            0x27 => {
-                println!("Warning: Instruction DAA not implemented");
-            },
+            0x27 => self.daa(),
             // Complement register
             0x2F => {
                 let x = self.reg.read(Register::A) ^ 0xFF;
@@ -1292,7 +1377,13 @@ impl Cpu {
             0x00 => (),
             // Halt CPU
             0x76 => {
-                self.state = CpuState::Halted;
+                if self.ime != ImeState::Enabled && self.pending_interrupts() != 0 {
+                    // HALT bug: the CPU never halts, and fails to increment
+                    // PC when fetching the next instruction
+                    self.halt_bug = true;
+                } else {
+                    self.state = CpuState::Halted;
+                }
             },
             // Stop CPU, maybe other instructions?
             0x10 => {
@@ -1306,8 +1397,12 @@ impl Cpu {
                 }
             },
             // Enable/disable interrupts
-            0xF3 => self.intlevel = false,
-            0xFB => self.intlevel = true,
+            0xF3 => self.ime = ImeState::Disabled,
+            0xFB => {
+                if self.ime == ImeState::Disabled {
+                    self.ime = ImeState::Pending;
+                }
+            },
             // Left rotate A
             0x07 => {
                 let a = self.reg.read(Register::A);
@@ -1532,11 +1627,17 @@ impl Cpu {
                 let addr = self.ram.read_u16(sp);
                 self.reg.set_pc(addr);
                 self.reg.write_u16(Register::SP, sp + 2);
-                self.intlevel = true;
+                // Unlike EI, takes effect immediately
+                self.ime = ImeState::Enabled;
             },
 
             _ => panic!("Instruction not implemented! Opcode {:X}", instr.opcode()),
         }
+        // EI only enables interrupts once the following instruction is done,
+        // unless that instruction was a DI
+        if enable_ime && self.ime == ImeState::Pending {
+            self.ime = ImeState::Enabled;
+        }
         let cycles = instr.cycles();
         self.clock += cycles as u64;
         cycles
@@ -1726,6 +1827,40 @@ impl Cpu {
         self.reg.set_flag(RegFlag::HalfCarry, true);
     }
 
+    /// Decimal adjust A, after a BCD add or subtract
+    ///
+    /// The subtract flag tells which operation came before, and the half carry
+    /// and carry flags tell which digits overflowed past 9.
+    pub fn daa(&mut self) {
+        let a = self.reg.read(Register::A);
+        let subtract = self.reg.get_flag(RegFlag::Subtract);
+        let half_carry = self.reg.get_flag(RegFlag::HalfCarry);
+        let mut carry = self.reg.get_flag(RegFlag::Carry);
+        let mut adjust = 0;
+        let x = if subtract {
+            if half_carry {
+                adjust |= 0x06;
+            }
+            if carry {
+                adjust |= 0x60;
+            }
+            a.wrapping_sub(adjust)
+        } else {
+            if half_carry || (a & 0x0F) > 0x09 {
+                adjust |= 0x06;
+            }
+            if carry || a > 0x99 {
+                adjust |= 0x60;
+                carry = true;
+            }
+            a.wrapping_add(adjust)
+        };
+        self.reg.set_flag(RegFlag::Zero, x == 0);
+        self.reg.set_flag(RegFlag::HalfCarry, false);
+        self.reg.set_flag(RegFlag::Carry, carry);
+        self.reg.write(Register::A, x);
+    }
+
     pub fn restart(&mut self, addr: u16) {
         println!("Warning: RST {:X}h only partially implemented", addr);
         println!("PC before RST: {:X}", self.reg.get_pc());
@@ -1745,4 +1880,411 @@ impl Cpu {
         // Make sure we start at the ROM entry point
         self.reg.set_pc(0x000);
     }
+
+    /// Start from the state the DMG boot ROM leaves behind, at the cartridge
+    /// entry point, instead of running a boot ROM
+    pub fn post_boot_init(&mut self) {
+        self.reg.write_u16(Register::AF, 0x01B0);
+        self.reg.write_u16(Register::BC, 0x0013);
+        self.reg.write_u16(Register::DE, 0x00D8);
+        self.reg.write_u16(Register::HL, 0x014D);
+        self.reg.write_u16(Register::SP, 0xFFFE);
+        self.reg.set_pc(0x0100);
+        self.ime = ImeState::Disabled;
+        self.ram.post_boot_init();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use joypad::Button;
+    use mem::Register;
+    use mem::RegFlag;
+
+    const PROGRAM_ADDR: u16 = 0xC000;
+
+    /// Load a program into work RAM, and point the CPU at it
+    fn load_program(cpu: &mut Cpu, program: &[u8]) {
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.ram.write(PROGRAM_ADDR + i as u16, byte);
+        }
+        cpu.reg.set_pc(PROGRAM_ADDR);
+    }
+
+    fn set_flags(cpu: &mut Cpu, flags: u8) {
+        cpu.reg.set_flag(RegFlag::Zero, flags & 0x80 != 0);
+        cpu.reg.set_flag(RegFlag::Subtract, flags & 0x40 != 0);
+        cpu.reg.set_flag(RegFlag::HalfCarry, flags & 0x20 != 0);
+        cpu.reg.set_flag(RegFlag::Carry, flags & 0x10 != 0);
+    }
+
+    fn get_flags(cpu: &Cpu) -> u8 {
+        let mut flags = 0;
+        if cpu.reg.get_flag(RegFlag::Zero) { flags |= 0x80 }
+        if cpu.reg.get_flag(RegFlag::Subtract) { flags |= 0x40 }
+        if cpu.reg.get_flag(RegFlag::HalfCarry) { flags |= 0x20 }
+        if cpu.reg.get_flag(RegFlag::Carry) { flags |= 0x10 }
+        flags
+    }
+
+    fn to_bcd(x: u32) -> u8 {
+        (((x / 10) << 4) | (x % 10)) as u8
+    }
+
+    /// Runs DAA with the given A register and flags, returning A and flags
+    fn daa(a: u8, flags: u8) -> (u8, u8) {
+        let mut cpu = Cpu::new();
+        load_program(&mut cpu, &[0x27]);
+        cpu.reg.write(Register::A, a);
+        set_flags(&mut cpu, flags);
+        assert_eq!(cpu.do_instr(), 4);
+        (cpu.reg.read(Register::A), get_flags(&cpu))
+    }
+
+    #[test]
+    fn stop_wakes_on_button() {
+        let mut cpu = Cpu::new();
+        load_program(&mut cpu, &[0x10, 0x00, 0x00]);
+        cpu.ram.write(mem::IOREG_P1, 0x10);
+        cpu.do_instr();
+        assert!(cpu.is_stopped());
+        // A button on the deselected row is ignored
+        cpu.ram.set_button(Button::Up, true);
+        cpu.do_instr();
+        assert!(cpu.is_stopped());
+        cpu.ram.set_button(Button::Start, true);
+        cpu.do_instr();
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 3);
+    }
+
+    #[test]
+    fn flags_live_in_f() {
+        let mut cpu = Cpu::new();
+        cpu.reg.write_u16(Register::AF, 0x12FF);
+        // The low nibble can't be set
+        assert_eq!(cpu.reg.read_u16(Register::AF), 0x12F0);
+        assert!(cpu.reg.get_flag(RegFlag::Zero));
+        cpu.reg.set_flag(RegFlag::Zero, false);
+        assert_eq!(cpu.reg.read_u16(Register::AF), 0x1270);
+    }
+
+    #[test]
+    fn post_boot_state() {
+        let mut cpu = Cpu::new();
+        cpu.init();
+        cpu.post_boot_init();
+        assert_eq!(cpu.reg.read_u16(Register::AF), 0x01B0);
+        assert_eq!(cpu.reg.read_u16(Register::BC), 0x0013);
+        assert_eq!(cpu.reg.read_u16(Register::DE), 0x00D8);
+        assert_eq!(cpu.reg.read_u16(Register::HL), 0x014D);
+        assert_eq!(cpu.reg.read_u16(Register::SP), 0xFFFE);
+        assert_eq!(cpu.reg.get_pc(), 0x0100);
+        assert!(cpu.reg.get_flag(RegFlag::Carry));
+        assert_eq!(cpu.ram.read(mem::IOREG_IF), 0xE1);
+        assert_eq!(cpu.ram.read(mem::IOREG_BGP), 0xFC);
+        assert_eq!(cpu.ram.read(0xFF26), 0xF1);
+    }
+
+    #[test]
+    fn daa_known_values() {
+        // (A, flags in, A out, flags out)
+        let cases = [
+            (0x00, 0x00, 0x00, 0x80),
+            (0x0A, 0x00, 0x10, 0x00),
+            (0x9A, 0x00, 0x00, 0x90),
+            (0x99, 0x00, 0x99, 0x00),
+            (0x3C, 0x20, 0x42, 0x00),
+            (0x12, 0x10, 0x72, 0x10),
+            (0xA0, 0x00, 0x00, 0x90),
+            (0x0F, 0x60, 0x09, 0x40),
+            (0xF0, 0x50, 0x90, 0x50),
+            (0x00, 0x70, 0x9A, 0x50),
+        ];
+        for &(a, flags, expect_a, expect_flags) in cases.iter() {
+            assert_eq!(daa(a, flags), (expect_a, expect_flags),
+                "DAA with A={:02X} F={:02X}", a, flags);
+        }
+    }
+
+    #[test]
+    fn daa_flag_matrix() {
+        for a in 0..0x100u32 {
+            for f in 0..0x10u8 {
+                let flags = f << 4;
+                let (x, out) = daa(a as u8, flags);
+                // Subtract is preserved, half carry is always cleared
+                assert_eq!(out & 0x40, flags & 0x40);
+                assert_eq!(out & 0x20, 0);
+                assert_eq!(out & 0x80 != 0, x == 0);
+                // A carry going in is never lost
+                if flags & 0x10 != 0 {
+                    assert!(out & 0x10 != 0);
+                }
+                // Adding a valid BCD number leaves it untouched
+                if flags & 0x70 == 0 && a & 0x0F <= 9 && a <= 0x99 {
+                    assert_eq!((x, out & 0x10), (a as u8, 0));
+                }
+                // Subtraction never sets a carry on its own
+                if flags & 0x50 == 0x40 {
+                    assert_eq!(out & 0x10, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn daa_bcd_add() {
+        for x in 0..100 {
+            for y in 0..100 {
+                let mut cpu = Cpu::new();
+                // ADD A, y; DAA
+                load_program(&mut cpu, &[0xC6, to_bcd(y), 0x27]);
+                cpu.reg.write(Register::A, to_bcd(x));
+                cpu.do_instr();
+                cpu.do_instr();
+                let sum = x + y;
+                assert_eq!(cpu.reg.read(Register::A), to_bcd(sum % 100), "{} + {}", x, y);
+                assert_eq!(cpu.reg.get_flag(RegFlag::Carry), sum >= 100, "{} + {}", x, y);
+                assert_eq!(cpu.reg.get_flag(RegFlag::Zero), sum % 100 == 0, "{} + {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn daa_bcd_sub() {
+        for x in 0..100 {
+            for y in 0..100 {
+                let mut cpu = Cpu::new();
+                // SUB A, y; DAA
+                load_program(&mut cpu, &[0xD6, to_bcd(y), 0x27]);
+                cpu.reg.write(Register::A, to_bcd(x));
+                cpu.do_instr();
+                cpu.do_instr();
+                let diff = (x + 100 - y) % 100;
+                assert_eq!(cpu.reg.read(Register::A), to_bcd(diff), "{} - {}", x, y);
+                assert_eq!(cpu.reg.get_flag(RegFlag::Carry), y > x, "{} - {}", x, y);
+                assert_eq!(cpu.reg.get_flag(RegFlag::Zero), diff == 0, "{} - {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupt_priority_dispatch() {
+        let mut cpu = Cpu::new();
+        load_program(&mut cpu, &[0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x1F);
+        cpu.request_interrupt(CpuInterrupt::TimerOverflow);
+        cpu.request_interrupt(CpuInterrupt::Joypad);
+        cpu.request_interrupt(CpuInterrupt::LcdStat);
+        assert_eq!(cpu.ram.read(mem::IOREG_IF), 0xE0 | 0x16);
+
+        // Highest priority first, with its request bit cleared
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(cpu.reg.get_pc(), 0x0048);
+        assert_eq!(cpu.ram.read(mem::IOREG_IF), 0xE0 | 0x14);
+        let sp = cpu.reg.read_u16(Register::SP);
+        assert_eq!(sp, 0xFFFC);
+        assert_eq!(cpu.ram.read_u16(sp), PROGRAM_ADDR);
+        // Nested interrupts wait until re-enabled
+        assert_eq!(cpu.ime, ImeState::Disabled);
+        assert_eq!(cpu.pending_interrupts(), 0x14);
+    }
+
+    #[test]
+    fn interrupt_masked() {
+        let mut cpu = Cpu::new();
+        load_program(&mut cpu, &[0x00, 0xF3, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.request_interrupt(CpuInterrupt::TimerOverflow);
+        // Not enabled in IE
+        assert_eq!(cpu.do_instr(), 4);
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 1);
+        // Disabled by DI
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        assert_eq!(cpu.do_instr(), 4);
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 3);
+        assert_eq!(cpu.ram.read(mem::IOREG_IF), 0xE0 | 0x05);
+    }
+
+    #[test]
+    fn halt_wakes_without_ime() {
+        let mut cpu = Cpu::new();
+        // DI; HALT; INC A
+        load_program(&mut cpu, &[0xF3, 0x76, 0x3C, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x04);
+        cpu.do_instr();
+        cpu.do_instr();
+        // Time still passes while halted
+        let clock = cpu.get_clock();
+        for _ in 0..10 {
+            assert_eq!(cpu.do_instr(), 4);
+        }
+        assert_eq!(cpu.get_clock(), clock + 40);
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 2);
+        // Wake on a pending interrupt, without calling the handler
+        cpu.request_interrupt(CpuInterrupt::TimerOverflow);
+        cpu.do_instr();
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 3);
+        assert_eq!(cpu.reg.read(Register::A), 1);
+        assert_eq!(cpu.ram.read(mem::IOREG_IF), 0xE0 | 0x04);
+    }
+
+    #[test]
+    fn halt_wakes_into_handler() {
+        let mut cpu = Cpu::new();
+        // HALT; NOP
+        load_program(&mut cpu, &[0x76, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(cpu.reg.get_pc(), 0x0040);
+        let sp = cpu.reg.read_u16(Register::SP);
+        assert_eq!(cpu.ram.read_u16(sp), PROGRAM_ADDR + 1);
+    }
+
+    #[test]
+    fn halt_bug() {
+        let mut cpu = Cpu::new();
+        // DI; HALT; INC A; NOP
+        load_program(&mut cpu, &[0xF3, 0x76, 0x3C, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x04);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::TimerOverflow);
+        cpu.do_instr();
+        // INC A is executed twice, as PC did not move past it the first time
+        cpu.do_instr();
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 2);
+        cpu.do_instr();
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 3);
+        assert_eq!(cpu.reg.read(Register::A), 2);
+    }
+
+    #[test]
+    fn halt_bug_reads_opcode_as_parameter() {
+        let mut cpu = Cpu::new();
+        // DI; HALT; LD A, 0x14
+        load_program(&mut cpu, &[0xF3, 0x76, 0x3E, 0x14]);
+        cpu.ram.write(mem::IOREG_IE, 0x04);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::TimerOverflow);
+        cpu.do_instr();
+        // Executes as LD A, 0x3E; INC D
+        cpu.do_instr();
+        assert_eq!(cpu.reg.read(Register::A), 0x3E);
+        cpu.do_instr();
+        assert_eq!(cpu.reg.read(Register::D), 1);
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 4);
+    }
+
+    /// Push a return address for RET/RETI to pop
+    fn push_return(cpu: &mut Cpu, addr: u16) {
+        let sp = cpu.reg.read_u16(Register::SP) - 2;
+        cpu.ram.write_u16(sp, addr);
+        cpu.reg.write_u16(Register::SP, sp);
+    }
+
+    fn stacked_pc(cpu: &Cpu) -> u16 {
+        cpu.ram.read_u16(cpu.reg.read_u16(Register::SP))
+    }
+
+    #[test]
+    fn ei_is_delayed() {
+        let mut cpu = Cpu::new();
+        // DI; EI; NOP; NOP
+        load_program(&mut cpu, &[0xF3, 0xFB, 0x00, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        cpu.do_instr();
+        assert_eq!(cpu.ime, ImeState::Pending);
+        // The instruction after EI runs before any interrupt
+        assert_eq!(cpu.do_instr(), 4);
+        assert_eq!(cpu.ime, ImeState::Enabled);
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(stacked_pc(&cpu), PROGRAM_ADDR + 3);
+    }
+
+    #[test]
+    fn ei_cancelled_by_di() {
+        let mut cpu = Cpu::new();
+        // DI; EI; DI; NOP; NOP
+        load_program(&mut cpu, &[0xF3, 0xFB, 0xF3, 0x00, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        for _ in 0..4 {
+            assert_eq!(cpu.do_instr(), 4);
+        }
+        assert_eq!(cpu.ime, ImeState::Disabled);
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 5);
+    }
+
+    #[test]
+    fn ei_ret() {
+        let mut cpu = Cpu::new();
+        // DI; EI; RET
+        load_program(&mut cpu, &[0xF3, 0xFB, 0xC9]);
+        push_return(&mut cpu, 0xC100);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        cpu.do_instr();
+        cpu.do_instr();
+        assert_eq!(cpu.reg.get_pc(), 0xC100);
+        // Serviced once RET completes, returning to the RET target
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(cpu.reg.get_pc(), 0x0040);
+        assert_eq!(stacked_pc(&cpu), 0xC100);
+    }
+
+    #[test]
+    fn ei_halt() {
+        let mut cpu = Cpu::new();
+        // DI; EI; HALT; NOP
+        load_program(&mut cpu, &[0xF3, 0xFB, 0x76, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        cpu.do_instr();
+        cpu.do_instr();
+        // The handler returns to the HALT, which then runs again
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(stacked_pc(&cpu), PROGRAM_ADDR + 2);
+        assert!(!cpu.halt_bug);
+    }
+
+    #[test]
+    fn ei_halt_waits_for_interrupt() {
+        let mut cpu = Cpu::new();
+        // DI; EI; HALT; NOP
+        load_program(&mut cpu, &[0xF3, 0xFB, 0x76, 0x00]);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        for _ in 0..5 {
+            cpu.do_instr();
+        }
+        assert_eq!(cpu.reg.get_pc(), PROGRAM_ADDR + 3);
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(stacked_pc(&cpu), PROGRAM_ADDR + 3);
+    }
+
+    #[test]
+    fn reti_is_immediate() {
+        let mut cpu = Cpu::new();
+        // DI; RETI
+        load_program(&mut cpu, &[0xF3, 0xD9]);
+        push_return(&mut cpu, 0xC100);
+        cpu.ram.write(mem::IOREG_IE, 0x01);
+        cpu.do_instr();
+        cpu.request_interrupt(CpuInterrupt::Vblank);
+        cpu.do_instr();
+        assert_eq!(cpu.ime, ImeState::Enabled);
+        assert_eq!(cpu.do_instr(), INTERRUPT_CYCLES);
+        assert_eq!(stacked_pc(&cpu), 0xC100);
+    }
 }
@@ -0,0 +1,389 @@
+//! Gamepads, mapped onto the joypad
+//!
+//! Controllers are read through a `PadBackend`, which reports buttons, analog
+//! sticks and hotplugging as `PadEvent`s. `Gamepads` tracks every connected
+//! pad, and turns their state into joypad button presses through a
+//! configurable `PadMapping`. The analog stick works the direction pad once it
+//! is pushed past a threshold.
+
+use std::collections::VecDeque;
+
+use joypad::{Button, BUTTONS};
+
+/// Buttons found on a typical controller, named by position
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PadButton {
+    South,
+    East,
+    North,
+    West,
+    Select,
+    Start,
+    LeftShoulder,
+    RightShoulder,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+pub const PAD_BUTTONS: [PadButton; 12] = [
+    PadButton::South,
+    PadButton::East,
+    PadButton::North,
+    PadButton::West,
+    PadButton::Select,
+    PadButton::Start,
+    PadButton::LeftShoulder,
+    PadButton::RightShoulder,
+    PadButton::DPadUp,
+    PadButton::DPadDown,
+    PadButton::DPadLeft,
+    PadButton::DPadRight,
+];
+
+impl PadButton {
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PadButton::South            => "south",
+            PadButton::East             => "east",
+            PadButton::North            => "north",
+            PadButton::West             => "west",
+            PadButton::Select           => "select",
+            PadButton::Start            => "start",
+            PadButton::LeftShoulder     => "leftshoulder",
+            PadButton::RightShoulder    => "rightshoulder",
+            PadButton::DPadUp           => "dpadup",
+            PadButton::DPadDown         => "dpaddown",
+            PadButton::DPadLeft         => "dpadleft",
+            PadButton::DPadRight        => "dpadright",
+        }
+    }
+
+    /// Look up a button by name, ignoring case
+    pub fn from_name(name: &str) -> Option<PadButton> {
+        let name = name.to_lowercase();
+        PAD_BUTTONS.iter().cloned().find(|b| b.name() == name)
+    }
+
+}
+
+/// Axes of the stick used as a direction pad
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PadAxis {
+    /// Positive to the right
+    StickX,
+    /// Positive upwards
+    StickY,
+}
+
+/// Something that happened on a controller, identified by a backend
+/// specific number
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PadEvent {
+    Connected(usize),
+    Disconnected(usize),
+    Button(usize, PadButton, bool),
+    /// Axis position, from -1.0 to 1.0
+    Axis(usize, PadAxis, f32),
+}
+
+/// Source of controller events
+pub trait PadBackend {
+
+    /// Take the next event, if there is one waiting
+    fn poll(&mut self) -> Option<PadEvent>;
+
+}
+
+/// Backend fed by hand, for tests and for frontends without controllers
+pub struct MockBackend {
+    events:     VecDeque<PadEvent>,
+}
+
+impl Default for MockBackend {
+
+    fn default() -> MockBackend {
+        MockBackend::new()
+    }
+
+}
+
+impl MockBackend {
+
+    pub fn new() -> MockBackend {
+        MockBackend {
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: PadEvent) {
+        self.events.push_back(event);
+    }
+
+}
+
+impl PadBackend for MockBackend {
+
+    fn poll(&mut self) -> Option<PadEvent> {
+        self.events.pop_front()
+    }
+
+}
+
+/// Default stick deflection needed to press a direction
+pub const DEFAULT_THRESHOLD: f32 = 0.5;
+
+/// Fraction of the threshold the stick must fall back under to release a
+/// direction, so a stick resting near the threshold doesn't chatter
+const RELEASE_FRACTION: f32 = 0.75;
+
+/// Which controller buttons press which joypad buttons, and how far the
+/// stick must be pushed
+pub struct PadMapping {
+    buttons:    Vec<(PadButton, Button)>,
+    threshold:  f32,
+}
+
+impl Default for PadMapping {
+
+    fn default() -> PadMapping {
+        PadMapping::new()
+    }
+
+}
+
+impl PadMapping {
+
+    /// The D-pad as itself, south and east for B and A as on most layouts,
+    /// and Start and Select as themselves
+    pub fn new() -> PadMapping {
+        PadMapping {
+            buttons: vec![
+                (PadButton::DPadRight, Button::Right),
+                (PadButton::DPadLeft, Button::Left),
+                (PadButton::DPadUp, Button::Up),
+                (PadButton::DPadDown, Button::Down),
+                (PadButton::East, Button::A),
+                (PadButton::South, Button::B),
+                (PadButton::Select, Button::Select),
+                (PadButton::Start, Button::Start),
+            ],
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+
+    /// Rebind joypad buttons from a list like `a=south,b=west`. Each joypad
+    /// button listed loses the controller buttons it had, and each controller
+    /// button listed stops pressing whatever it pressed before. With the
+    /// default layout, `a=south` leaves B unbound until it is given a button
+    /// of its own.
+    pub fn parse(&mut self, spec: &str) -> Result<(), String> {
+        for binding in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut parts = binding.splitn(2, '=');
+            let button_name = parts.next().unwrap_or("").trim();
+            let pad_name = parts.next().unwrap_or("").trim();
+            let button = match Button::from_name(button_name) {
+                Some(b) => b,
+                None => return Err(format!("Unknown button \"{}\"", button_name)),
+            };
+            let pad_button = match PadButton::from_name(pad_name) {
+                Some(b) => b,
+                None => return Err(format!("Unknown controller button \"{}\"", pad_name)),
+            };
+            self.buttons.retain(|&(p, b)| b != button && p != pad_button);
+            self.buttons.push((pad_button, button));
+        }
+        Ok(())
+    }
+
+    /// Set the stick deflection needed to press a direction, from 0 to 1
+    pub fn set_threshold(&mut self, threshold: f32) -> Result<(), String> {
+        if threshold > 0.0 && threshold < 1.0 {
+            self.threshold = threshold;
+            Ok(())
+        } else {
+            Err(format!("Stick threshold {} is not between 0 and 1", threshold))
+        }
+    }
+
+    pub fn button(&self, pad_button: PadButton) -> Option<Button> {
+        self.buttons.iter().find(|&&(p, _)| p == pad_button).map(|&(_, b)| b)
+    }
+
+}
+
+/// State of one connected controller, as joypad button masks
+struct Pad {
+    id:         usize,
+    buttons:    u8,
+    stick:      u8,
+}
+
+/// Every connected controller, combined into one joypad
+pub struct Gamepads<B: PadBackend> {
+    backend:    B,
+    mapping:    PadMapping,
+    pads:       Vec<Pad>,
+    /// Joypad buttons held, as last reported
+    held:       u8,
+}
+
+impl<B: PadBackend> Gamepads<B> {
+
+    pub fn new(backend: B, mapping: PadMapping) -> Gamepads<B> {
+        Gamepads {
+            backend: backend,
+            mapping: mapping,
+            pads: Vec::new(),
+            held: 0,
+        }
+    }
+
+    pub fn backend(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Number of controllers connected
+    pub fn connected(&self) -> usize {
+        self.pads.len()
+    }
+
+    /// Handle every waiting event, and return the joypad buttons which were
+    /// pressed or released. A button held on several controllers is only
+    /// released once none of them hold it.
+    pub fn poll(&mut self) -> Vec<(Button, bool)> {
+        while let Some(event) = self.backend.poll() {
+            self.handle(event);
+        }
+        let held = self.pads.iter().fold(0, |acc, pad| acc | pad.buttons | pad.stick);
+        let changed = held ^ self.held;
+        self.held = held;
+        BUTTONS.iter()
+            .filter(|b| (changed & b.mask()) != 0)
+            .map(|&b| (b, (held & b.mask()) != 0))
+            .collect()
+    }
+
+    fn handle(&mut self, event: PadEvent) {
+        match event {
+            PadEvent::Connected(id) => {
+                if self.pad(id).is_none() {
+                    self.pads.push(Pad { id: id, buttons: 0, stick: 0 });
+                }
+            },
+            // Releases whatever the controller held
+            PadEvent::Disconnected(id) => self.pads.retain(|pad| pad.id != id),
+            PadEvent::Button(id, pad_button, pressed) => {
+                if let Some(button) = self.mapping.button(pad_button) {
+                    let pad = self.pad_or_connect(id);
+                    if pressed {
+                        pad.buttons |= button.mask();
+                    } else {
+                        pad.buttons &= !button.mask();
+                    }
+                }
+            },
+            PadEvent::Axis(id, axis, value) => {
+                let press = self.mapping.threshold;
+                let release = press * RELEASE_FRACTION;
+                let (negative, positive) = match axis {
+                    PadAxis::StickX => (Button::Left, Button::Right),
+                    PadAxis::StickY => (Button::Down, Button::Up),
+                };
+                let pad = self.pad_or_connect(id);
+                for &(button, deflection) in [(negative, -value), (positive, value)].iter() {
+                    let held = (pad.stick & button.mask()) != 0;
+                    if deflection >= press || (held && deflection >= release) {
+                        pad.stick |= button.mask();
+                    } else {
+                        pad.stick &= !button.mask();
+                    }
+                }
+            },
+        }
+    }
+
+    fn pad(&mut self, id: usize) -> Option<&mut Pad> {
+        self.pads.iter_mut().find(|pad| pad.id == id)
+    }
+
+    /// Backends may report input from a pad before announcing it
+    fn pad_or_connect(&mut self, id: usize) -> &mut Pad {
+        if self.pad(id).is_none() {
+            self.pads.push(Pad { id: id, buttons: 0, stick: 0 });
+        }
+        self.pad(id).unwrap()
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use joypad::Button;
+
+    fn gamepads() -> Gamepads<MockBackend> {
+        Gamepads::new(MockBackend::new(), PadMapping::new())
+    }
+
+    #[test]
+    fn buttons_and_hotplug() {
+        let mut pads = gamepads();
+        pads.backend().push(PadEvent::Connected(3));
+        pads.backend().push(PadEvent::Button(3, PadButton::East, true));
+        pads.backend().push(PadEvent::Button(3, PadButton::North, true));
+        assert_eq!(pads.poll(), vec![(Button::A, true)]);
+        assert_eq!(pads.connected(), 1);
+        // A second controller holding the same button
+        pads.backend().push(PadEvent::Connected(4));
+        pads.backend().push(PadEvent::Button(4, PadButton::East, true));
+        pads.backend().push(PadEvent::Button(4, PadButton::Start, true));
+        assert_eq!(pads.poll(), vec![(Button::Start, true)]);
+        pads.backend().push(PadEvent::Button(3, PadButton::East, false));
+        assert_eq!(pads.poll(), vec![]);
+        // Unplugging releases everything it held
+        pads.backend().push(PadEvent::Disconnected(4));
+        assert_eq!(pads.poll(), vec![(Button::A, false), (Button::Start, false)]);
+        assert_eq!(pads.connected(), 1);
+    }
+
+    #[test]
+    fn stick_thresholds() {
+        let mut pads = gamepads();
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickX, 0.3));
+        assert_eq!(pads.poll(), vec![]);
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickX, 0.6));
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickY, -0.9));
+        assert_eq!(pads.poll(), vec![(Button::Right, true), (Button::Down, true)]);
+        // Held until well back under the threshold
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickX, 0.45));
+        assert_eq!(pads.poll(), vec![]);
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickX, -0.2));
+        assert_eq!(pads.poll(), vec![(Button::Right, false)]);
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickY, 0.8));
+        assert_eq!(pads.poll(), vec![(Button::Up, true), (Button::Down, false)]);
+    }
+
+    #[test]
+    fn custom_mapping() {
+        let mut mapping = PadMapping::new();
+        mapping.parse("a=south").unwrap();
+        // South no longer presses B, which is left without a button
+        assert_eq!(mapping.button(PadButton::East), None);
+        assert!(!mapping.buttons.iter().any(|&(_, b)| b == Button::B));
+        mapping.parse("b=west").unwrap();
+        assert_eq!(mapping.button(PadButton::South), Some(Button::A));
+        assert_eq!(mapping.button(PadButton::West), Some(Button::B));
+        assert_eq!(mapping.button(PadButton::East), None);
+        assert!(mapping.parse("turbo=north").is_err());
+        assert!(mapping.parse("a=paddle").is_err());
+        assert!(mapping.set_threshold(1.5).is_err());
+        mapping.set_threshold(0.25).unwrap();
+        let mut pads = Gamepads::new(MockBackend::new(), mapping);
+        pads.backend().push(PadEvent::Button(0, PadButton::South, true));
+        pads.backend().push(PadEvent::Axis(0, PadAxis::StickX, -0.3));
+        assert_eq!(pads.poll(), vec![(Button::Left, true), (Button::A, true)]);
+    }
+}
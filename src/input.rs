@@ -0,0 +1,176 @@
+use glium::glutin::VirtualKeyCode;
+use glium::glutin::VirtualKeyCode as Key;
+
+use gameboy_rust::joypad::Button;
+#[cfg(not(feature = "gamepad"))]
+use gameboy_rust::gamepad::MockBackend;
+
+#[cfg(feature = "gamepad")]
+pub use self::gilrs_backend::GilrsBackend;
+
+/// Keys which can be bound to buttons, named as in glutin
+const BINDABLE_KEYS: &'static [VirtualKeyCode] = &[
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::Numpad0, Key::Numpad1, Key::Numpad2, Key::Numpad3, Key::Numpad4,
+    Key::Numpad5, Key::Numpad6, Key::Numpad7, Key::Numpad8, Key::Numpad9,
+    Key::Left, Key::Up, Key::Right, Key::Down,
+    Key::Return, Key::Space, Key::Back, Key::Tab,
+    Key::LShift, Key::RShift, Key::LControl, Key::RControl, Key::LAlt, Key::RAlt,
+    Key::Comma, Key::Period, Key::Semicolon, Key::Slash, Key::Apostrophe,
+];
+
+/// Look up a key by its glutin name, ignoring case
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    BINDABLE_KEYS.iter().cloned().find(|key| format!("{:?}", key).eq_ignore_ascii_case(name))
+}
+
+/// Which keyboard keys press which joypad buttons
+pub struct KeyBindings {
+    keys:   Vec<(VirtualKeyCode, Button)>,
+}
+
+impl KeyBindings {
+
+    /// Arrow keys for the direction pad, X and Z for A and B, Enter for Start
+    /// and right Shift for Select
+    pub fn new() -> KeyBindings {
+        KeyBindings {
+            keys: vec![
+                (Key::Right, Button::Right),
+                (Key::Left, Button::Left),
+                (Key::Up, Button::Up),
+                (Key::Down, Button::Down),
+                (Key::X, Button::A),
+                (Key::Z, Button::B),
+                (Key::RShift, Button::Select),
+                (Key::Return, Button::Start),
+            ],
+        }
+    }
+
+    /// Rebind buttons from a list like `a=S,b=A,select=Back`. Each button
+    /// listed loses its old key.
+    pub fn parse(&mut self, spec: &str) -> Result<(), String> {
+        for binding in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut parts = binding.splitn(2, '=');
+            let button_name = parts.next().unwrap_or("").trim();
+            let key_name = parts.next().unwrap_or("").trim();
+            let button = match Button::from_name(button_name) {
+                Some(b) => b,
+                None => return Err(format!("Unknown button \"{}\"", button_name)),
+            };
+            let key = match key_from_name(key_name) {
+                Some(k) => k,
+                None => return Err(format!("Unknown key \"{}\"", key_name)),
+            };
+            self.keys.retain(|&(k, b)| b != button && k != key);
+            self.keys.push((key, button));
+        }
+        Ok(())
+    }
+
+    pub fn button(&self, key: VirtualKeyCode) -> Option<Button> {
+        self.keys.iter().find(|&&(k, _)| k == key).map(|&(_, b)| b)
+    }
+
+}
+
+/// Controller backend for this build. Without the `gamepad` feature, no
+/// controllers are ever connected.
+#[cfg(feature = "gamepad")]
+pub fn open_pad_backend() -> GilrsBackend {
+    GilrsBackend::new()
+}
+
+#[cfg(not(feature = "gamepad"))]
+pub fn open_pad_backend() -> MockBackend {
+    MockBackend::new()
+}
+
+#[cfg(feature = "gamepad")]
+mod gilrs_backend {
+    use gilrs;
+    use gilrs::{Axis, EventType, Gilrs};
+
+    use gameboy_rust::gamepad::{PadAxis, PadBackend, PadButton, PadEvent};
+
+    /// Controllers through gilrs, which reads evdev on Linux
+    pub struct GilrsBackend {
+        gilrs:  Option<Gilrs>,
+    }
+
+    impl GilrsBackend {
+
+        pub fn new() -> GilrsBackend {
+            let gilrs = match Gilrs::new() {
+                Ok(g) => Some(g),
+                Err(e) => {
+                    println!("Gamepads unavailable: {}", e);
+                    None
+                },
+            };
+            GilrsBackend {
+                gilrs: gilrs,
+            }
+        }
+
+    }
+
+    impl PadBackend for GilrsBackend {
+
+        fn poll(&mut self) -> Option<PadEvent> {
+            let gilrs = match self.gilrs {
+                Some(ref mut g) => g,
+                None => return None,
+            };
+            // Skip over anything without a meaning on the joypad
+            while let Some(ev) = gilrs.next_event() {
+                let id: usize = ev.id.into();
+                let event = match ev.event {
+                    EventType::Connected => Some(PadEvent::Connected(id)),
+                    EventType::Disconnected => Some(PadEvent::Disconnected(id)),
+                    EventType::ButtonPressed(b, _) => {
+                        pad_button(b).map(|b| PadEvent::Button(id, b, true))
+                    },
+                    EventType::ButtonReleased(b, _) => {
+                        pad_button(b).map(|b| PadEvent::Button(id, b, false))
+                    },
+                    EventType::AxisChanged(Axis::LeftStickX, value, _) => {
+                        Some(PadEvent::Axis(id, PadAxis::StickX, value))
+                    },
+                    EventType::AxisChanged(Axis::LeftStickY, value, _) => {
+                        Some(PadEvent::Axis(id, PadAxis::StickY, value))
+                    },
+                    _ => None,
+                };
+                if event.is_some() {
+                    return event;
+                }
+            }
+            None
+        }
+
+    }
+
+    fn pad_button(button: gilrs::Button) -> Option<PadButton> {
+        match button {
+            gilrs::Button::South        => Some(PadButton::South),
+            gilrs::Button::East         => Some(PadButton::East),
+            gilrs::Button::North        => Some(PadButton::North),
+            gilrs::Button::West         => Some(PadButton::West),
+            gilrs::Button::Select       => Some(PadButton::Select),
+            gilrs::Button::Start        => Some(PadButton::Start),
+            gilrs::Button::LeftTrigger  => Some(PadButton::LeftShoulder),
+            gilrs::Button::RightTrigger => Some(PadButton::RightShoulder),
+            gilrs::Button::DPadUp       => Some(PadButton::DPadUp),
+            gilrs::Button::DPadDown     => Some(PadButton::DPadDown),
+            gilrs::Button::DPadLeft     => Some(PadButton::DPadLeft),
+            gilrs::Button::DPadRight    => Some(PadButton::DPadRight),
+            _ => None,
+        }
+    }
+}
@@ -22,6 +22,18 @@ impl Instr {
 
     pub fn parse(reg: &mut RegData, rom: &AddressSpace) -> Instr {
         let opcode = rom.read(reg.advance_pc());
+        Instr::decode(opcode, reg, rom)
+    }
+
+    /// Parse an instruction without moving PC past the opcode, as happens to
+    /// the first fetch after the HALT bug triggers. Any parameters are read
+    /// starting from the opcode byte.
+    pub fn parse_halt_bug(reg: &mut RegData, rom: &AddressSpace) -> Instr {
+        let opcode = rom.read(reg.get_pc());
+        Instr::decode(opcode, reg, rom)
+    }
+
+    fn decode(opcode: u8, reg: &mut RegData, rom: &AddressSpace) -> Instr {
         let (vec, cycles) = match opcode {
             // LD reg, immed
             0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x3E => {
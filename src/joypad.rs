@@ -0,0 +1,159 @@
+//! Joypad buttons, and the P1 register they are read through
+//!
+//! The eight buttons sit on a matrix of four input lines, with two select
+//! lines choosing between the direction pad and the other buttons. Both the
+//! inputs and the selects are active low, so a pressed button on a selected
+//! row reads as 0.
+
+/// One of the eight joypad buttons
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+pub const BUTTONS: [Button; 8] = [
+    Button::Right,
+    Button::Left,
+    Button::Up,
+    Button::Down,
+    Button::A,
+    Button::B,
+    Button::Select,
+    Button::Start,
+];
+
+impl Button {
+
+    /// Bit of the button in the joypad state. The low nibble holds the
+    /// direction pad, and the high nibble the other buttons, each in the
+    /// order of their P1 input lines.
+    pub fn mask(&self) -> u8 {
+        match *self {
+            Button::Right   => 0x01,
+            Button::Left    => 0x02,
+            Button::Up      => 0x04,
+            Button::Down    => 0x08,
+            Button::A       => 0x10,
+            Button::B       => 0x20,
+            Button::Select  => 0x40,
+            Button::Start   => 0x80,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Button::Right   => "right",
+            Button::Left    => "left",
+            Button::Up      => "up",
+            Button::Down    => "down",
+            Button::A       => "a",
+            Button::B       => "b",
+            Button::Select  => "select",
+            Button::Start   => "start",
+        }
+    }
+
+    /// Look up a button by name, ignoring case
+    pub fn from_name(name: &str) -> Option<Button> {
+        let name = name.to_lowercase();
+        BUTTONS.iter().cloned().find(|b| b.name() == name)
+    }
+
+}
+
+/// Which buttons are held down
+pub struct Joypad {
+    pressed:    u8,
+}
+
+impl Joypad {
+
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: 0,
+        }
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        (self.pressed & button.mask()) != 0
+    }
+
+    /// Value read from P1, given the select lines in bits 4 and 5 of `p1`.
+    /// Bit 4 low selects the direction pad, and bit 5 low the other buttons.
+    pub fn read_p1(&self, p1: u8) -> u8 {
+        let mut lines = 0x0F;
+        if (p1 & 0x10) == 0 {
+            lines &= !(self.pressed & 0x0F);
+        }
+        if (p1 & 0x20) == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        0xC0 | (p1 & 0x30) | lines
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CpuInterrupt;
+    use mem;
+    use mem::AddressSpace;
+
+    #[test]
+    fn select_lines() {
+        let mut joypad = Joypad::new();
+        joypad.set(Button::Down, true);
+        joypad.set(Button::Start, true);
+        joypad.set(Button::A, true);
+        joypad.set(Button::A, false);
+        assert_eq!(joypad.read_p1(0x30), 0xFF);
+        assert_eq!(joypad.read_p1(0x20), 0xE7);
+        assert_eq!(joypad.read_p1(0x10), 0xD7);
+        assert_eq!(joypad.read_p1(0x00), 0xC7);
+        assert_eq!(Button::from_name("Select"), Some(Button::Select));
+        assert_eq!(Button::from_name("turbo"), None);
+    }
+
+    #[test]
+    fn p1_interrupt() {
+        let mut ram = AddressSpace::new();
+        ram.write(mem::IOREG_P1, 0x20);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xEF);
+        // Only buttons on a selected row pull a line low
+        ram.set_button(Button::A, true);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xEF);
+        assert_eq!(ram.read(mem::IOREG_IF) & CpuInterrupt::Joypad.mask(), 0);
+        ram.set_button(Button::Left, true);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xED);
+        assert!(ram.read(mem::IOREG_IF) & CpuInterrupt::Joypad.mask() != 0);
+        // Selecting a row with a button held also counts as a press
+        ram.write(mem::IOREG_IF, 0);
+        ram.write(mem::IOREG_P1, 0x10);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xDE);
+        assert!(ram.read(mem::IOREG_IF) & CpuInterrupt::Joypad.mask() != 0);
+        // Releasing does not
+        ram.write(mem::IOREG_IF, 0);
+        ram.set_button(Button::A, false);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xDF);
+        assert_eq!(ram.read(mem::IOREG_IF) & CpuInterrupt::Joypad.mask(), 0);
+        // The input lines can't be written
+        ram.write(mem::IOREG_P1, 0x00);
+        assert_eq!(ram.read(mem::IOREG_P1), 0xCD);
+    }
+}
@@ -0,0 +1,17 @@
+//! Gameboy emulation core
+//!
+//! The library contains everything needed to run a Gameboy machine without a
+//! display attached. Frontends drive a `machine::Machine`, and read back the
+//! rendered frame through `Machine::framebuffer`.
+
+mod instr;
+pub mod apu;
+pub mod audio;
+pub mod cart;
+pub mod clock;
+pub mod cpu;
+pub mod gamepad;
+pub mod joypad;
+pub mod machine;
+pub mod mem;
+pub mod ppu;
@@ -0,0 +1,254 @@
+use std::io;
+use std::io::{Read, Write};
+
+use cart::CartEvent;
+use cart::rtc;
+use cart::save;
+use clock::{Clock, IntType};
+use cpu;
+use cpu::Cpu;
+use joypad::Button;
+use mem::AddressSpace;
+use ppu::{Ppu, Renderer};
+
+/// A complete Gameboy, minus the display
+///
+/// Owns the CPU (and through it the address space, cartridge and APU), the
+/// PPU, and the hardware timers. Frontends drive the machine one instruction
+/// or one frame at a time, and read the output through `framebuffer` and
+/// `take_samples`.
+pub struct Machine {
+    cpu:    Cpu,
+    ppu:    Ppu,
+    clock:  Clock,
+}
+
+impl Machine {
+
+    pub fn new() -> Machine {
+        let mut cpu = Cpu::new();
+        cpu.init();
+        // Initialize virtual hardware clocks
+        let mut clock = Clock::new();
+        clock.set_interrupt(IntType::Lcd, Ppu::first_step());
+        clock.set_interrupt(IntType::IoTimer, cpu::TIMER_BASE_PERIOD);
+        Machine {
+            cpu: cpu,
+            ppu: Ppu::new(),
+            clock: clock,
+        }
+    }
+
+    pub fn load_bios<R: Read>(&mut self, bios: &mut R) -> io::Result<()> {
+        self.cpu.get_ram().load_bios(bios)
+    }
+
+    /// Start at the cartridge entry point, with the registers set up as the
+    /// boot ROM would leave them. Use instead of `load_bios`.
+    pub fn skip_bios(&mut self) {
+        self.cpu.post_boot_init();
+    }
+
+    pub fn load_rom<R: Read>(&mut self, rom: &mut R) -> io::Result<()> {
+        self.cpu.get_ram().load_rom(rom)
+    }
+
+    /// Whether the cartridge has battery backed RAM worth saving
+    pub fn has_battery(&mut self) -> bool {
+        self.cpu.get_ram().has_battery()
+    }
+
+    /// Returns true if the cartridge RAM changed since the last call, so the
+    /// frontend can decide when to autosave
+    pub fn take_save_dirty(&mut self) -> bool {
+        self.cpu.get_ram().take_cart_dirty()
+    }
+
+    /// Write cartridge RAM and clock in the `.sav` format
+    pub fn save_ram<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        save::write_save(self.cpu.get_ram().get_cart(), out, rtc::host_seconds())
+    }
+
+    /// Load cartridge RAM and clock from a `.sav` file
+    pub fn load_ram<R: Read>(&mut self, input: &mut R) -> io::Result<()> {
+        save::read_save(self.cpu.get_ram().get_cart(), input, rtc::host_seconds())
+    }
+
+    pub fn get_cpu(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn get_ram(&mut self) -> &mut AddressSpace {
+        self.cpu.get_ram()
+    }
+
+    pub fn get_ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    /// Switch between the scanline and pixel FIFO renderers, from the next
+    /// line on
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.ppu.set_renderer(renderer);
+    }
+
+    /// Number of cycles emulated since power on
+    pub fn get_clock(&self) -> u64 {
+        self.cpu.get_clock()
+    }
+
+    /// Execute a single instruction, and handle any hardware events that
+    /// occurred while it ran. Returns the number of cycles taken.
+    pub fn step_instruction(&mut self) -> u32 {
+        let cycles = self.cpu.do_instr();
+        if let Some(rtc) = self.cpu.get_ram().get_cart().rtc() {
+            rtc.tick(cycles);
+        }
+        self.cpu.get_ram().tick_dma(cycles);
+        self.cpu.get_ram().tick_div(cycles);
+        self.cpu.get_ram().get_apu().tick(cycles);
+        let now = self.cpu.get_clock();
+        if self.cpu.get_ram().take_lcd_dirty() {
+            // Switching the LCD on or off restarts its timing
+            if let Some(next) = self.ppu.update_lcdc(self.cpu.get_ram()) {
+                self.clock.cancel_interrupt(IntType::Lcd);
+                self.clock.set_interrupt(IntType::Lcd, now + next);
+            }
+            self.ppu.update_stat(self.cpu.get_ram());
+        }
+        while let Some((int, target)) = self.clock.next_interrupt(now) {
+            self.handle_event(int, target);
+        }
+        cycles
+    }
+
+    /// Run until the PPU finishes drawing a frame
+    pub fn run_frame(&mut self) {
+        loop {
+            self.step_instruction();
+            if self.ppu.take_frame() {
+                return;
+            }
+        }
+    }
+
+    /// Press or release a joypad button
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.get_ram().set_button(button, pressed);
+    }
+
+    /// Set the rate of the audio samples returned by `take_samples`, in Hz
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.cpu.get_ram().get_apu().set_sample_rate(rate);
+    }
+
+    /// Move the audio produced so far onto the end of `out`, as interleaved
+    /// stereo samples, left first
+    pub fn take_samples(&mut self, out: &mut Vec<i16>) {
+        self.cpu.get_ram().get_apu().take_samples(out);
+    }
+
+    /// Start or stop producing a separate stream of samples for each sound
+    /// channel, alongside the mix
+    pub fn set_stems(&mut self, enabled: bool) {
+        self.cpu.get_ram().get_apu().set_stems(enabled);
+    }
+
+    /// Move the audio produced so far for sound channel `channel`, from 0 to
+    /// 3, onto the end of `out`, in the same format as `take_samples`
+    pub fn take_stem_samples(&mut self, channel: usize, out: &mut Vec<i16>) {
+        self.cpu.get_ram().get_apu().take_stem_samples(channel, out);
+    }
+
+    /// Take the oldest event from the cartridge, such as the rumble motor
+    /// switching on or off. Frontends should poll this until empty after each
+    /// frame.
+    pub fn poll_cart_event(&mut self) -> Option<CartEvent> {
+        self.cpu.get_ram().get_cart().poll_event()
+    }
+
+    /// The most recently completed frame. See `ppu::Ppu` for the format.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    /// Dispatch an event scheduled for cycle `target`. Periodic events are
+    /// rescheduled relative to `target`, so they never drift.
+    fn handle_event(&mut self, int: IntType, target: u64) {
+        match int {
+            // PPU mode change
+            IntType::Lcd => {
+                let next = self.ppu.step(self.cpu.get_ram());
+                self.clock.set_interrupt(IntType::Lcd, target + next);
+            },
+            // Do timer computations
+            IntType::IoTimer => {
+                self.clock.set_interrupt(IntType::IoTimer, target + cpu::TIMER_BASE_PERIOD);
+                self.cpu.inc_io_timer();
+            },
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::IOREG_LCDC;
+    use ppu::{LCD_HEIGHT, LCD_WIDTH};
+
+    /// A ROM filling tile 0 with color 3, which covers the whole background,
+    /// then spinning in place
+    fn dark_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        let code = [
+            0x21, 0x00, 0x80,   // LD HL,$8000
+            0x3E, 0xFF,         // LD A,$FF
+            0x06, 0x10,         // LD B,16
+            0x22,               // LD (HL+),A
+            0x05,               // DEC B
+            0x20, 0xFC,         // JR NZ,-4
+            0x18, 0xFE,         // JR -2
+        ];
+        rom[0x100..0x100 + code.len()].copy_from_slice(&code);
+        rom
+    }
+
+    fn start(rom: &[u8]) -> Machine {
+        let mut machine = Machine::new();
+        machine.load_rom(&mut &rom[..]).unwrap();
+        machine.skip_bios();
+        machine
+    }
+
+    #[test]
+    fn bios_starts_with_lcd_off() {
+        let mut machine = Machine::new();
+        // JR -2, spinning in place
+        let bios = [0x18, 0xFE];
+        machine.load_bios(&mut &bios[..]).unwrap();
+        machine.step_instruction();
+        assert!(!machine.get_ppu().is_enabled());
+        // Switching it on from the boot ROM restarts the LCD timing
+        machine.get_ram().write(IOREG_LCDC, 0x91);
+        machine.step_instruction();
+        assert!(machine.get_ppu().is_enabled());
+        assert_eq!(machine.get_ppu().get_ly(), 0);
+    }
+
+    #[test]
+    fn frames_are_deterministic() {
+        let rom = dark_rom();
+        let mut a = start(&rom);
+        let mut b = start(&rom);
+        for _ in 0..2 {
+            a.run_frame();
+            b.run_frame();
+            assert_eq!(a.get_clock(), b.get_clock());
+            assert_eq!(a.framebuffer().len(), (LCD_WIDTH * LCD_HEIGHT) as usize);
+            assert_eq!(a.framebuffer(), b.framebuffer());
+        }
+        // The second frame is drawn entirely after the tile was filled in
+        assert!(a.framebuffer().iter().all(|&x| x == 3));
+    }
+}
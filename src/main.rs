@@ -1,11 +1,11 @@
-use instr::Instr;
-use cpu::Cpu;
-use cpu::CpuInterrupt;
 use time::precise_time_ns;
+use std::fs;
 use std::fs::File;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::Duration;
 use std::sync::mpsc;
 use std::sync::mpsc::TryRecvError;
 
@@ -15,126 +15,122 @@ use glium::SwapBuffersError;
 use glium::glutin::Api;
 use glium::glutin::GlRequest;
 use glium::glutin::Event;
+use glium::glutin::{ElementState, VirtualKeyCode};
 
-use mem::{RwMemory, WriteObserver};
+use gameboy_rust::audio::RateControl;
+use gameboy_rust::cart;
+use gameboy_rust::cart::{CartEvent, CartridgeHeader};
+use gameboy_rust::cpu;
+use gameboy_rust::gamepad::{Gamepads, PadMapping};
+use gameboy_rust::joypad::Button;
+use gameboy_rust::machine::Machine;
+use gameboy_rust::ppu::{LCD_WIDTH, LCD_HEIGHT, Renderer};
 
 extern crate time;
 extern crate getopts;
 #[macro_use]
 extern crate glium;
 extern crate cgmath;
+#[cfg(feature = "gamepad")]
+extern crate gilrs;
+#[cfg(feature = "audio")]
+extern crate cpal;
+extern crate gameboy_rust;
 
-mod instr;
-mod cpu;
-mod mem;
+mod input;
 mod render;
+mod sound;
 
-#[derive(Copy, Clone)]
-pub enum IntType {
-    Vblank,
-    Hblank,
-    IoTimer,
-}
+const NS_PER_S: u64 = 1_000_000_000;
+const NS_PER_MS: u64 = 1_000_000;
 
-struct ClockInt {
-    pub int_target: u64,
-    pub int_type: IntType,
-}
+// 100ms
+const MAX_PACING_LAG: u64 = 100_000_000;
 
-impl PartialEq for ClockInt {
-    #[inline]
-    fn eq(&self, other: &Self) -> bool {
-        self.int_target == other.int_target
-    }
+/// Frames between autosaves of battery backed RAM, ~5 seconds
+const AUTOSAVE_FRAMES: u32 = 300;
+
+/// Keeps emulated time in step with the wall clock
+///
+/// The machine only counts cycles, so the frontend sleeps between frames until
+/// real time catches up with emulated time. Only used when the audio output
+/// doesn't pace the machine itself.
+struct Pacer {
+    base_ns:        u64,
+    base_cycles:    u64,
 }
 
-impl Eq for ClockInt {}
+impl Pacer {
 
-impl PartialOrd for ClockInt {
-    #[inline]
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        match self.int_target.partial_cmp(&other.int_target) {
-            Some(Ordering::Less) => Some(Ordering::Greater),
-            Some(Ordering::Greater) => Some(Ordering::Less),
-            ord => ord,
+    fn new(cycles: u64) -> Pacer {
+        Pacer {
+            base_ns: precise_time_ns(),
+            base_cycles: cycles,
         }
     }
-}
 
-impl Ord for ClockInt {
-    #[inline]
-    fn cmp(&self, other: &Self) -> Ordering {
-        match self.int_target.cmp(&other.int_target) {
-            Ordering::Less => Ordering::Greater,
-            Ordering::Greater => Ordering::Less,
-            ord => ord,
+    /// Wait until the machine's cycle counter `cycles` is due in real time
+    fn wait(&mut self, cycles: u64) {
+        let elapsed = (cycles - self.base_cycles) * NS_PER_S / cpu::GB_FREQUENCY as u64;
+        let target = self.base_ns + elapsed;
+        let now = precise_time_ns();
+        if target > now {
+            thread::sleep(Duration::from_nanos(target - now));
+            self.base_ns = target;
+        } else if now - target > MAX_PACING_LAG {
+            // Too far behind to catch up, so drop the lost time
+            self.base_ns = now;
+        } else {
+            self.base_ns = target;
         }
+        self.base_cycles = cycles;
     }
-}
 
-const NS_PER_S: u64 = 1_000_000_000;
-const NS_PER_MS: u64 = 1_000_000;
-
-// 10ms
-const BUSY_WAIT_THRESHOLD: u64 = 10_000_000;
-
-pub struct Clock {
-    freq:       u32,
-    period:     u64,
-    int_heap:   BinaryHeap<ClockInt>,
 }
 
-impl Clock {
-
-    pub fn new(freq: u32) -> Clock {
-        Clock {
-            freq: freq,
-            period: NS_PER_S / (freq as u64),
-            int_heap: BinaryHeap::new(),
-        }
+/// Write battery backed RAM to `path`, through a temporary file so a crash
+/// never leaves a half written save behind
+fn write_save_file(machine: &mut Machine, path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("sav.tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(machine.save_ram(&mut file));
     }
+    fs::rename(&tmp, path)
+}
 
-    pub fn set_interrupt(&mut self, itype: IntType, period: u64) {
-        let start = precise_time_ns();
-        let int = ClockInt {
-            int_type: itype,
-            int_target: start + period,
-        };
-        self.int_heap.push(int);
+/// Print the header of the ROM at `path`, and check that the emulator can run
+/// it. Returns the process exit code.
+fn print_info(path: &str) -> i32 {
+    let mut data = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        println!("Error reading file: {}", e);
+        return 1;
     }
-
-    pub fn wait_cycles(&mut self, n: u32) -> Option<IntType> {
-        let start = precise_time_ns();
-        let real_wait = self.period * (n as u64);
-        let real_target = real_wait + start;
-        let (target, result) = if let Some(interrupt) = self.int_heap.pop() {
-            if real_target > interrupt.int_target {
-                (interrupt.int_target, Some(interrupt.int_type))
-            } else {
-                self.int_heap.push(interrupt);
-                (real_target, None)
-            }
-        } else {
-            (real_target, None)
-        };
-        let mut curtime = start;
-        if target > start && target - start > BUSY_WAIT_THRESHOLD {
-            std::thread::sleep_ms(((target - start) / NS_PER_MS) as u32);
-            return result;
-        } else {
-            loop {
-                if curtime >= target {
-                    return result;
-                }
-                curtime = precise_time_ns();
-            }
-        }
+    let header = match CartridgeHeader::parse(&data) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("Error: {}", e);
+            return 1;
+        },
+    };
+    println!("{}", header);
+    match cart::load_cartridge(data, &header) {
+        Ok(_) => 0,
+        Err(e) => {
+            println!("Error: {}", e);
+            1
+        },
     }
-
 }
 
 pub enum WorkerCmd {
-    TakeSnapshot(Box<RwMemory>, WriteObserver),
+    /// Copy the latest frame into the buffer, and send it back
+    TakeFrame(Vec<u8>),
+    /// Switch the PPU renderer
+    SetRenderer(Renderer),
+    /// Press or release a joypad button
+    Button(Button, bool),
     Shutdown,
 }
 
@@ -142,6 +138,17 @@ fn main() {
     //  Gather command line args
     let args: Vec<String> = std::env::args().collect();
     let mut opts = getopts::Options::new();
+    opts.optopt("", "bios", "Run a boot ROM before the cartridge", "PATH");
+    opts.optflag("", "info", "Print the cartridge header and exit");
+    opts.optflag("", "rtc-host", "Run the cartridge clock from host time instead of emulated time");
+    opts.optopt("", "renderer", "Draw with the scanline (default) or pixel FIFO renderer", "scanline|fifo");
+    opts.optopt("", "keys", "Rebind joypad buttons to keys", "BUTTON=KEY,...");
+    opts.optopt("", "pad-map", "Rebind joypad buttons to controller buttons", "BUTTON=PAD,...");
+    opts.optopt("", "pad-threshold", "Stick deflection which presses a direction, from 0 to 1", "AMOUNT");
+    opts.optopt("", "audio", "Play sound through cpal, discard it, or write it to a WAV file", "cpal|null|wav=PATH");
+    opts.optopt("", "record-audio", "Run without a window, writing the sound to a WAV file", "PATH");
+    opts.optopt("", "frames", "Number of frames to record with --record-audio", "N");
+    opts.optflag("", "stems", "Also record each sound channel to a file of its own");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m },
         Err(e) => panic!("Error: {}", e),
@@ -153,25 +160,64 @@ fn main() {
         return;
     };
 
-    // Build graphics context and window
-    let display = glium::glutin::WindowBuilder::new()
-        .with_title("Gameboy Rust".to_string())
-        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 2)))
-        .build_glium()
-        .unwrap();
+    if matches.opt_present("info") {
+        std::process::exit(print_info(&input));
+    }
+
+    let record_frames = match matches.opt_str("frames").map(|n| n.parse::<u32>()) {
+        None => None,
+        Some(Ok(n)) => Some(n),
+        Some(Err(e)) => {
+            println!("Error in frame count: {}", e);
+            return;
+        },
+    };
+    let record_path = matches.opt_str("record-audio");
+    if record_path.is_some() != record_frames.is_some() {
+        println!("--record-audio and --frames must be given together");
+        return;
+    }
+
+    let mut renderer = match matches.opt_str("renderer").as_ref().map(|s| s.as_str()) {
+        None | Some("scanline") => Renderer::Scanline,
+        Some("fifo") => Renderer::Fifo,
+        Some(other) => {
+            println!("Unknown renderer: {}", other);
+            return;
+        },
+    };
+
+    let mut bindings = input::KeyBindings::new();
+    if let Some(spec) = matches.opt_str("keys") {
+        if let Err(e) = bindings.parse(&spec) {
+            println!("Error in key bindings: {}", e);
+            return;
+        }
+    }
+
+    let mut pad_mapping = PadMapping::new();
+    if let Some(spec) = matches.opt_str("pad-map") {
+        if let Err(e) = pad_mapping.parse(&spec) {
+            println!("Error in controller mapping: {}", e);
+            return;
+        }
+    }
+    if let Some(amount) = matches.opt_str("pad-threshold") {
+        let result = amount.parse::<f32>()
+            .map_err(|e| e.to_string())
+            .and_then(|x| pad_mapping.set_threshold(x));
+        if let Err(e) = result {
+            println!("Error in stick threshold: {}", e);
+            return;
+        }
+    }
+    let mut gamepads = Gamepads::new(input::open_pad_backend(), pad_mapping);
+    let mut pads_connected = 0;
 
     // Do machine initialization
-    let mut cpu = Cpu::new();
-    cpu.init();
+    let mut machine = Machine::new();
+    machine.set_renderer(renderer);
     {
-        let mut ram = cpu.get_ram();
-        let mut biosfile = match File::open(std::path::Path::new("rom/bios.bin")) {
-            Ok(f) => { f },
-            Err(e) => {
-                println!("Error opening bios file");
-                return;
-            },
-        };
         let mut romfile = match File::open(std::path::Path::new(&input)) {
             Ok(f) => { f },
             Err(e) => {
@@ -179,14 +225,70 @@ fn main() {
                 return;
             }
         };
-        if let Err(e) = ram.load_bios(&mut biosfile) {
-            println!("Error loading bios data: {}", e);
-            return;
+        match matches.opt_str("bios") {
+            Some(path) => {
+                let mut biosfile = match File::open(Path::new(&path)) {
+                    Ok(f) => { f },
+                    Err(e) => {
+                        println!("Error opening bios file: {}", e);
+                        return;
+                    },
+                };
+                if let Err(e) = machine.load_bios(&mut biosfile) {
+                    println!("Error loading bios data: {}", e);
+                    return;
+                }
+            },
+            None => machine.skip_bios(),
         }
-        if let Err(e) = ram.load_rom(&mut romfile) {
+        if let Err(e) = machine.load_rom(&mut romfile) {
             println!("Error loading rom data: {}", e);
             return;
         }
+        if matches.opt_present("rtc-host") {
+            if let Some(rtc) = machine.get_ram().get_cart().rtc() {
+                rtc.set_host_sync(true);
+            }
+        }
+    }
+
+    // Recordings start from a clean power on, without the save file, so they
+    // come out the same every run
+    if let (Some(path), Some(frames)) = (record_path, record_frames) {
+        let stems = matches.opt_present("stems");
+        if let Err(e) = sound::record_audio(&mut machine, Path::new(&path), frames, stems) {
+            println!("Error recording audio: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let audio_spec = matches.opt_str("audio").unwrap_or(sound::DEFAULT_SINK.to_string());
+    let mut audio = match sound::open_sink(&audio_spec) {
+        Ok(sink) => sink,
+        Err(e) => {
+            println!("Error opening audio output: {}", e);
+            return;
+        },
+    };
+    machine.set_sample_rate(audio.sample_rate());
+
+    // Build graphics context and window
+    let display = glium::glutin::WindowBuilder::new()
+        .with_title("Gameboy Rust".to_string())
+        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 2)))
+        .build_glium()
+        .unwrap();
+
+    // Battery backed RAM lives next to the ROM
+    let save_path: PathBuf = Path::new(&input).with_extension("sav");
+    if machine.has_battery() {
+        if let Ok(mut savefile) = File::open(&save_path) {
+            if let Err(e) = machine.load_ram(&mut savefile) {
+                println!("Error loading save file: {}", e);
+                return;
+            }
+        }
     }
 
     // Initialize virtual LCD
@@ -204,78 +306,78 @@ fn main() {
         .name("simulation worker".to_string())
         .spawn(move || {
 
-        // Initialize virtual hardware clocks
-        let mut clock = Clock::new(cpu::GB_FREQUENCY);
-        clock.set_interrupt(IntType::Vblank, render::VBLANK_PERIOD);
-        clock.set_interrupt(IntType::Hblank, render::HBLANK_PERIOD);
-        clock.set_interrupt(IntType::IoTimer, cpu::TIMER_BASE_PERIOD_NS);
-
-        // TODO: Abstract LCD simulation better
-        // Track ly here
-        let mut ly = 0;
+        let mut pacer = Pacer::new(machine.get_clock());
+        let rate_control = RateControl::new(audio.sample_rate());
+        let mut samples = Vec::new();
+        let mut frames_since_save = 0;
 
         'main: loop {
             // Simulate CPU and hardware timers
-            'sim: loop  {
-                if let Some(int) = clock.wait_cycles(cpu.do_instr()) {
-                    // Handle timer interrupt
-                    match int {
-                        // Interrupt at the start of the vblank period
-                        IntType::Vblank => {
-                            clock.set_interrupt(IntType::Vblank, render::VBLANK_PERIOD);
-                            cpu.interrupt(CpuInterrupt::Vblank);
-                            ly = 144; // set_ly_vblank
-                            let ram = cpu.get_ram();
-                            ram.sys_write(mem::IOREG_LY, ly);
-                        }
-                        // ~10 H-Blanks occur after the V-Blank starts
-                        IntType::Hblank => {
-                            clock.set_interrupt(IntType::Hblank, render::HBLANK_PERIOD);
-                            // inc_ly_counter
-                            if ly >= 153 {
-                                ly = 0;
-                            } else {
-                                ly += 1
-                            }
-                            let ram = cpu.get_ram();
-                            ram.sys_write(mem::IOREG_LY, ly);
-                            // At the end, collect data from VRAM and render it
-                            if ly == 0 {
-                                break 'sim;
-                            }
-                        }
-                        // Do timer computations
-                        IntType::IoTimer => {
-                            clock.set_interrupt(IntType::IoTimer, cpu::TIMER_BASE_PERIOD_NS);
-                            cpu.inc_io_timer();
-                        }
+            machine.run_frame();
+
+            // Real time outputs block until there is room for the frame's
+            // sound, which paces the machine. Otherwise follow the wall clock.
+            samples.clear();
+            machine.take_samples(&mut samples);
+            match audio.fill() {
+                Some(fill) => machine.set_sample_rate(rate_control.rate(fill)),
+                None => pacer.wait(machine.get_clock()),
+            }
+            if let Err(e) = audio.write(&samples) {
+                println!("Error writing audio: {}", e);
+            }
+
+            frames_since_save += 1;
+            if frames_since_save >= AUTOSAVE_FRAMES {
+                frames_since_save = 0;
+                if machine.has_battery() && machine.take_save_dirty() {
+                    if let Err(e) = write_save_file(&mut machine, &save_path) {
+                        println!("Error writing save file: {}", e);
                     }
                 }
             }
+            while let Some(event) = machine.poll_cart_event() {
+                match event {
+                    CartEvent::Rumble(on) => println!("Rumble {}", if on { "on" } else { "off" }),
+                }
+            }
 
-            // Check commands from master
-            match sim_rx.try_recv() {
-                Ok(WorkerCmd::TakeSnapshot(oldsnap, mut observer)) => {
-                    let ram = cpu.get_ram();
-                    let newsnap = ram.swap_backup(oldsnap);
-                    ram.get_observer().apply(&mut observer);
-                    sim_tx.send((newsnap, observer));
-                    if !ram.verify_backup() {
-                        println!("Backup verify failed!")
+            // Check commands from master, including any input since the
+            // last frame
+            loop {
+                match sim_rx.try_recv() {
+                    Ok(WorkerCmd::TakeFrame(mut frame)) => {
+                        frame.copy_from_slice(machine.framebuffer());
+                        sim_tx.send(frame);
+                    },
+                    Ok(WorkerCmd::SetRenderer(renderer)) => {
+                        machine.set_renderer(renderer);
+                    },
+                    Ok(WorkerCmd::Button(button, pressed)) => {
+                        machine.set_button(button, pressed);
+                    },
+                    Ok(WorkerCmd::Shutdown) => {
+                        if let Err(e) = audio.finish() {
+                            println!("Error finishing audio: {}", e);
+                        }
+                        if machine.has_battery() {
+                            if let Err(e) = write_save_file(&mut machine, &save_path) {
+                                println!("Error writing save file: {}", e);
+                            }
+                        }
+                        break 'main;
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        panic!("I/O thread disconnected without notifying");
                     }
-                },
-                Ok(WorkerCmd::Shutdown) => break 'main,
-                Err(TryRecvError::Empty) => (),
-                Err(TryRecvError::Disconnected) => {
-                    panic!("I/O thread disconnected without notifying");
                 }
             }
         }
     });
 
-    // Create a memory snapshot, and write observer
-    let mut oldsnap = Some(Box::new(RwMemory::new()));
-    let mut oldobserver = Some(WriteObserver::new());
+    // Buffer passed back and forth with the simulation
+    let mut oldframe = Some(vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize]);
 
     // Simulate CPU
     'main: loop {
@@ -290,15 +392,37 @@ fn main() {
                     let (width, height) = window.unwrap().get_inner_size_pixels().unwrap();
                     viewport = render::calculate_viewport(width, height);
                 },
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::F2)) => {
+                    // Toggle between renderers
+                    renderer = match renderer {
+                        Renderer::Scanline => Renderer::Fifo,
+                        Renderer::Fifo => Renderer::Scanline,
+                    };
+                    println!("Renderer: {:?}", renderer);
+                    io_tx.send(WorkerCmd::SetRenderer(renderer));
+                },
+                Event::KeyboardInput(state, _, Some(key)) => {
+                    if let Some(button) = bindings.button(key) {
+                        io_tx.send(WorkerCmd::Button(button, state == ElementState::Pressed));
+                    }
+                },
                 _ => (),
             }
         }
 
-        // Request memory snapshot from simulation
-        io_tx.send(WorkerCmd::TakeSnapshot(oldsnap.take().unwrap(), oldobserver.take().unwrap()));
-        let (snapshot, mut observer) = match io_rx.recv() {
+        for (button, pressed) in gamepads.poll() {
+            io_tx.send(WorkerCmd::Button(button, pressed));
+        }
+        if gamepads.connected() != pads_connected {
+            pads_connected = gamepads.connected();
+            println!("Gamepads connected: {}", pads_connected);
+        }
+
+        // Request the latest frame from simulation
+        io_tx.send(WorkerCmd::TakeFrame(oldframe.take().unwrap()));
+        let frame = match io_rx.recv() {
             Ok(v) => v,
-            Err(_) => panic!("Did not receive snapshot from simulation thread"),
+            Err(_) => panic!("Did not receive frame from simulation thread"),
         };
 
         // Redraw screen
@@ -312,7 +436,7 @@ fn main() {
             println!("clear time: {}ms", clear_time);
         }
         let pre_draw = precise_time_ns();
-        lcd.draw(&display, &mut target, viewport, &snapshot, &mut observer);
+        lcd.draw(&mut target, viewport, &frame);
         let post_draw = precise_time_ns();
         let draw_time = (post_draw - pre_draw) as f32 / NS_PER_MS as f32;
         if draw_time > 5.0f32 {
@@ -335,10 +459,12 @@ fn main() {
             println!("flush time: {}ms", flush_time);
         }
 
-        oldsnap = Some(snapshot);
-        oldobserver = Some(observer);
+        oldframe = Some(frame);
     }
 
-    // Shutdown sim thread
+    // Shutdown sim thread, and wait for it to flush the save file
     io_tx.send(WorkerCmd::Shutdown);
+    if let Ok(worker) = sim_worker {
+        worker.join();
+    }
 }
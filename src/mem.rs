@@ -1,7 +1,12 @@
-use std::fs::File;
+use std::io;
 use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
+
+use apu;
+use apu::Apu;
+use cart;
+use cart::{Cartridge, CartridgeHeader, RomOnly};
+use cpu::CpuInterrupt;
+use joypad::{Button, Joypad};
 use std::ops::Index;
 use std::ops::IndexMut;
 
@@ -11,110 +16,6 @@ pub enum MemSection {
     RomBank0,
 }
 
-pub const REGION_TILEDATA_UNSIGNED_BEG: u16 = 0x8000;
-pub const REGION_TILEDATA_UNSIGNED_END: u16 = 0x8FFF;
-pub const REGION_TILEDATA_SIGNED_BEG:   u16 = 0x8800;
-pub const REGION_TILEDATA_SIGNED_END:   u16 = 0x97FF;
-pub const REGION_TILEMAP0_BEG:          u16 = 0x9800;
-pub const REGION_TILEMAP0_END:          u16 = 0x9BFF;
-pub const REGION_TILEMAP1_BEG:          u16 = 0x9C00;
-pub const REGION_TILEMAP1_END:          u16 = 0x9FFF;
-
-pub enum Region {
-    TileDataUnsigned,
-    TileDataSigned,
-    TileMap0,
-    TileMap1,
-}
-
-pub struct WriteObserver {
-    td_signed_dirty:    bool,
-    td_unsigned_dirty:  bool,
-    tmap0_dirty:        bool,
-    tmap1_dirty:        bool,
-}
-
-impl WriteObserver {
-
-    pub fn new() -> WriteObserver {
-        WriteObserver {
-            td_signed_dirty:    true,
-            td_unsigned_dirty:  true,
-            tmap0_dirty:        true,
-            tmap1_dirty:        true,
-        }
-    }
-
-    pub fn record_write(&mut self, addr: u16) {
-        match addr {
-            REGION_TILEDATA_UNSIGNED_BEG...REGION_TILEDATA_UNSIGNED_END => {
-                self.td_unsigned_dirty = true;
-            },
-            REGION_TILEDATA_SIGNED_BEG...REGION_TILEDATA_SIGNED_END => {
-                self.td_signed_dirty = true;
-            },
-            REGION_TILEMAP0_BEG...REGION_TILEMAP0_END => {
-                self.tmap0_dirty = true;
-            },
-            REGION_TILEMAP1_BEG...REGION_TILEMAP1_END => {
-                self.tmap1_dirty = true;
-            },
-            _ => (),
-        }
-    }
-
-    pub fn check_dirty(&self, region: Region) -> bool {
-        match region {
-            Region::TileDataUnsigned => {
-                self.td_unsigned_dirty
-            },
-            Region::TileDataSigned => {
-                self.td_signed_dirty
-            },
-            Region::TileMap0 => {
-                self.tmap0_dirty
-            },
-            Region::TileMap1 => {
-                self.tmap1_dirty
-            },
-        }
-    }
-
-    pub fn clean_region(&mut self, region: Region) {
-        match region {
-            Region::TileDataUnsigned => {
-                self.td_unsigned_dirty = false;
-            },
-            Region::TileDataSigned => {
-                self.td_signed_dirty = false;
-            },
-            Region::TileMap0 => {
-                self.tmap0_dirty = false;
-            },
-            Region::TileMap1 => {
-                self.tmap1_dirty = false;
-            },
-        }
-    }
-
-    /// Applies dirtiness to another WriteObserver, then cleans self
-    pub fn apply(&mut self, other: &mut WriteObserver) {
-        other.td_unsigned_dirty |= self.td_unsigned_dirty;
-        other.td_signed_dirty |= self.td_signed_dirty;
-        other.tmap0_dirty |= self.tmap0_dirty;
-        other.tmap1_dirty |= self.tmap1_dirty;
-        self.clean_all();
-    }
-
-    pub fn clean_all(&mut self) {
-        self.td_unsigned_dirty = false;
-        self.td_signed_dirty = false;
-        self.tmap0_dirty = false;
-        self.tmap1_dirty = false;
-    }
-
-}
-
 pub struct RwMemory {
     data: [u8; 0x10000],
 }
@@ -126,10 +27,6 @@ impl RwMemory {
             data: [0; 0x10000],
         }
     }
-
-    pub fn copy_to(&self, other: &mut RwMemory) {
-        other.data.copy_from_slice(&self.data);
-    }
 }
 
 impl Index<u16> for RwMemory {
@@ -146,17 +43,54 @@ impl IndexMut<u16> for RwMemory {
     }
 }
 
-// TODO: Separate ROM from RwMemory
+/// Cycles for OAM DMA to copy each byte, one machine cycle
+pub const DMA_BYTE_CYCLES: u32 = 4;
+
+/// Bytes copied by an OAM DMA, the whole of OAM
+pub const DMA_LENGTH: u16 = 0xA0;
+
+const OAM_ADDR: u16 = 0xFE00;
+
+/// Bit of the internal divider whose falling edge steps the APU frame
+/// sequencer, bit 4 of DIV
+const SEQUENCER_DIV_BIT: u16 = 0x1000;
+
+/// An OAM DMA in progress
+#[derive(Copy, Clone, Debug)]
+struct OamDma {
+    source:     u16,
+    /// Bytes copied so far
+    pos:        u16,
+}
+
 pub struct AddressSpace {
     bios:           [u8; 0x100],
+    cart:           Box<dyn Cartridge>,
+    /// Header of the loaded cartridge
+    header:         Option<CartridgeHeader>,
+    /// Cartridge RAM was written since the last save
+    cart_dirty:     bool,
+    /// LCDC, STAT or LYC was written, and the PPU must check the LCD and
+    /// STAT interrupt
+    lcd_dirty:      bool,
+    joypad:         Joypad,
+    /// OAM DMA in progress, which locks the CPU out of everything below the
+    /// I/O registers
+    dma:            Option<OamDma>,
+    /// Source of an OAM DMA written this instruction, to start once it ends
+    dma_request:    Option<u16>,
+    /// Cycles towards copying the next DMA byte
+    dma_cycles:     u32,
+    /// Internal 16-bit divider, whose high byte is DIV
+    div_counter:    u16,
+    apu:            Apu,
     main_ram:       RwMemory,
-    backup_ram:     Box<RwMemory>,
     bios_readable:  bool,
-    observer:       WriteObserver,
 }
 
 pub const IOREG_P1:     u16 = 0xFF00;
 pub const IOREG_SB:     u16 = 0xFF01;
+pub const IOREG_SC:     u16 = 0xFF02;
 pub const IOREG_DIV:    u16 = 0xFF04;
 pub const IOREG_TIMA:   u16 = 0xFF05;
 pub const IOREG_TMA:    u16 = 0xFF06;
@@ -177,21 +111,92 @@ pub const IOREG_WX:     u16 = 0xFF4B;
 pub const IOREG_BIOSRW: u16 = 0xFF50;
 pub const IOREG_IE:     u16 = 0xFFFF;
 
+/// I/O register values left behind by the DMG boot ROM
+const DMG_POST_BOOT_IO: [(u16, u8); 37] = [
+    (IOREG_P1,      0xCF),
+    (IOREG_SB,      0x00),
+    (IOREG_SC,      0x7E),
+    (IOREG_DIV,     0xAB),
+    (IOREG_TIMA,    0x00),
+    (IOREG_TMA,     0x00),
+    (IOREG_TAC,     0xF8),
+    (IOREG_IF,      0x01),
+    (0xFF10,        0x80), // NR10
+    (0xFF11,        0xBF), // NR11
+    (0xFF12,        0xF3), // NR12
+    (0xFF13,        0xFF), // NR13
+    (0xFF14,        0xBF), // NR14
+    (0xFF16,        0x3F), // NR21
+    (0xFF17,        0x00), // NR22
+    (0xFF18,        0xFF), // NR23
+    (0xFF19,        0xBF), // NR24
+    (0xFF1A,        0x7F), // NR30
+    (0xFF1B,        0xFF), // NR31
+    (0xFF1C,        0x9F), // NR32
+    (0xFF1D,        0xFF), // NR33
+    (0xFF1E,        0xBF), // NR34
+    (0xFF20,        0xFF), // NR41
+    (0xFF21,        0x00), // NR42
+    (0xFF22,        0x00), // NR43
+    (0xFF23,        0xBF), // NR44
+    (0xFF24,        0x77), // NR50
+    (0xFF25,        0xF3), // NR51
+    (0xFF26,        0xF1), // NR52
+    (IOREG_LCDC,    0x91),
+    (IOREG_STAT,    0x85),
+    (IOREG_DMA,     0xFF),
+    (IOREG_BGP,     0xFC),
+    (IOREG_OBP0,    0xFF),
+    (IOREG_OBP1,    0xFF),
+    (IOREG_BIOSRW,  0x01),
+    (IOREG_IE,      0x00),
+];
+
 impl AddressSpace {
 
     pub fn new() -> AddressSpace {
-        AddressSpace {
+        let mut ram = AddressSpace {
             bios: [0; 0x100],
+            cart: Box::new(RomOnly::empty()),
+            header: None,
+            cart_dirty: false,
+            lcd_dirty: false,
+            joypad: Joypad::new(),
+            dma: None,
+            dma_request: None,
+            dma_cycles: 0,
+            div_counter: 0,
+            apu: Apu::new(apu::DEFAULT_SAMPLE_RATE),
             main_ram: RwMemory::new(),
-            backup_ram: Box::new(RwMemory::new()),
             bios_readable: true,
-            observer: WriteObserver::new(),
-        }
+        };
+        ram.update_p1();
+        ram
     }
 
+    /// Read as the CPU. While OAM DMA runs, the CPU can only reach the I/O
+    /// registers and HRAM, and reads anywhere else float high.
     pub fn read(&self, addr: u16) -> u8 {
+        if self.dma.is_some() && addr < 0xFF00 {
+            0xFF
+        } else {
+            self.bus_read(addr)
+        }
+    }
+
+    /// Read without the restrictions of a running DMA
+    fn bus_read(&self, addr: u16) -> u8 {
         if addr < 0x100 && self.bios_readable {
             self.bios[addr as usize]
+        } else if addr < 0x8000 {
+            self.cart.read_rom(addr)
+        } else if (0xA000..0xC000).contains(&addr) {
+            self.cart.read_ram(addr)
+        } else if (apu::APU_START..=apu::APU_END).contains(&addr) {
+            self.apu.read(addr)
+        } else if addr == IOREG_IF {
+            // Unused bits always read high
+            self.main_ram[addr] | 0xE0
         } else {
             self.main_ram[addr]
         }
@@ -208,36 +213,72 @@ impl AddressSpace {
     }
 
     pub fn write(&mut self, addr: u16, data: u8) {
+        if self.dma.is_some() && addr < 0xFF00 {
+            return;
+        }
         let mut addr = addr;
         let mut data = data;
         let rw = match addr {
-            // ROM Banks, read only
-            0x0000...0x7FFF => false,
+            // ROM Banks, read only. Writes go to the bank controller
+            0x0000...0x7FFF => {
+                self.cart.write_rom(addr, data);
+                false
+            },
             // Switchable RAM bank
-            // TODO: Make RAM switchable
-            0xA000...0xBFFF => true,
+            0xA000...0xBFFF => {
+                if self.cart.write_ram(addr, data) {
+                    self.cart_dirty = true;
+                }
+                false
+            },
             // Internal RAM echo
             0xE000...0xFDFF => {
                 addr -= 0x2000;
                 true
             },
             // I/O registers
+            // Only the select lines can be written
+            IOREG_P1 => {
+                let p1 = (data & 0x30) | (self.main_ram[IOREG_P1] & 0xCF);
+                self.sys_write(IOREG_P1, p1);
+                self.update_p1();
+                false
+            },
+            // Resetting the divider can step the frame sequencer early
             IOREG_DIV => {
+                if (self.div_counter & SEQUENCER_DIV_BIT) != 0 {
+                    self.apu.step_sequencer();
+                }
+                self.div_counter = 0;
                 data = 0;
                 true
             },
-            IOREG_LY => {
-                data = 0;
+            apu::APU_START...apu::APU_END => {
+                self.apu.write(addr, data);
+                false
+            },
+            // LY is read only, and owned by the PPU
+            IOREG_LY => false,
+            // The mode and coincidence bits are read only
+            IOREG_STAT => {
+                data = (data & 0x78) | (self.main_ram[IOREG_STAT] & 0x87);
+                self.lcd_dirty = true;
+                true
+            },
+            IOREG_LCDC | IOREG_LYC => {
+                self.lcd_dirty = true;
+                true
+            },
+            // Only the five interrupt bits are latched
+            IOREG_IF => {
+                data &= 0x1F;
                 true
             },
-            // Don't write, but begin a DMA instead
+            // Begin an OAM DMA, once this instruction is done. Sources past
+            // work RAM read from its echo.
             IOREG_DMA => {
-                let to_addr = 0xFE00;
-                let from_addr = (data as u16) << 8;
-                for i in 0x000..0x100 {
-                    self.main_ram[to_addr + i] = self.main_ram[from_addr + i];
-                    self.backup_ram[to_addr + i] = self.main_ram[from_addr + i];
-                }
+                let source = (data as u16) << 8;
+                self.dma_request = Some(if source >= 0xE000 { source - 0x2000 } else { source });
                 true
             },
             // Disable access to BIOS memory
@@ -264,52 +305,149 @@ impl AddressSpace {
 
     /// System write, bypasses read-only flag
     pub fn sys_write(&mut self, addr: u16, data: u8) {
-        self.observer.record_write(addr);
         self.main_ram[addr] = data;
-        self.backup_ram[addr] = data;
     }
 
-    pub fn load_bios(&mut self, bios: &mut File) -> ::std::io::Result<()> {
+    pub fn load_bios<R: Read>(&mut self, bios: &mut R) -> ::std::io::Result<()> {
         try!(bios.read(&mut self.bios[0x000..0x100]));
+        // The boot ROM expects the LCD off at power on, and switches it on
+        // itself once the logo is in VRAM
+        self.sys_write(IOREG_LCDC, 0x00);
+        self.lcd_dirty = true;
         Ok(())
     }
 
-    pub fn load_rom(&mut self, rom: &mut File) -> ::std::io::Result<()> {
-        // Read in header first
-        try!(rom.read(&mut self.main_ram.data[0x000..0x150]));
-        // Then read in remaining cart data
-        try!(rom.read(&mut self.main_ram.data[0x0150..0x8000]));
+    pub fn load_rom<R: Read>(&mut self, rom: &mut R) -> io::Result<()> {
+        let mut data = Vec::new();
+        try!(rom.read_to_end(&mut data));
+        let header = try!(CartridgeHeader::parse(&data));
+        self.cart = try!(cart::load_cartridge(data, &header));
+        self.header = Some(header);
+        self.cart_dirty = false;
         Ok(())
     }
 
-    pub fn set_bios_readable(&mut self) {
-        self.bios_readable = true;
+    pub fn get_cart(&mut self) -> &mut dyn Cartridge {
+        &mut *self.cart
     }
 
-    pub fn get_observer(&mut self) -> &mut WriteObserver {
-        &mut self.observer
+    pub fn get_header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
     }
 
-    pub fn swap_backup(&mut self, mut mem: Box<RwMemory>) -> Box<RwMemory> {
-        ::std::mem::swap(&mut mem, &mut self.backup_ram);
-        let AddressSpace {
-            ref mut main_ram,
-            ref mut backup_ram,
-            ..
-        } = *self;
-        main_ram.copy_to(backup_ram);
-        mem
+    /// Cartridge RAM survives power off, and should be saved
+    pub fn has_battery(&self) -> bool {
+        match self.header {
+            Some(ref header) => header.has_battery(),
+            None => false,
+        }
+    }
+
+    /// Returns true if cartridge RAM was written since the last call
+    pub fn take_cart_dirty(&mut self) -> bool {
+        let dirty = self.cart_dirty;
+        self.cart_dirty = false;
+        dirty
+    }
+
+    /// Returns true if LCDC, STAT or LYC was written since the last call
+    pub fn take_lcd_dirty(&mut self) -> bool {
+        let dirty = self.lcd_dirty;
+        self.lcd_dirty = false;
+        dirty
+    }
+
+    /// Press or release a joypad button
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.joypad.set(button, pressed);
+        self.update_p1();
+    }
+
+    /// Refresh the input lines of P1, and raise the joypad interrupt when any
+    /// of them falls
+    fn update_p1(&mut self) {
+        let old = self.main_ram[IOREG_P1];
+        let new = self.joypad.read_p1(old);
+        self.sys_write(IOREG_P1, new);
+        if (old & !new & 0x0F) != 0 {
+            self.request_interrupt(CpuInterrupt::Joypad);
+        }
     }
 
-    pub fn verify_backup(&self) -> bool {
-        let mut i = 0;
-        while i < self.main_ram.data.len() {
-            if self.main_ram.data[i] != self.backup_ram.data[i] {
-                return false;
+    /// Whether an OAM DMA is copying, and the CPU is locked out of memory
+    pub fn dma_active(&self) -> bool {
+        self.dma.is_some()
+    }
+
+    /// Run OAM DMA for the cycles taken by the last instruction, copying one
+    /// byte per machine cycle. A DMA written by that instruction starts
+    /// afterwards, and replaces any transfer still running.
+    pub fn tick_dma(&mut self, cycles: u32) {
+        if let Some(mut dma) = self.dma.take() {
+            self.dma_cycles += cycles;
+            while self.dma_cycles >= DMA_BYTE_CYCLES && dma.pos < DMA_LENGTH {
+                self.dma_cycles -= DMA_BYTE_CYCLES;
+                let x = self.bus_read(dma.source + dma.pos);
+                self.main_ram[OAM_ADDR + dma.pos] = x;
+                dma.pos += 1;
+            }
+            if dma.pos < DMA_LENGTH {
+                self.dma = Some(dma);
+            }
+        }
+        if let Some(source) = self.dma_request.take() {
+            self.dma = Some(OamDma {
+                source: source,
+                pos: 0,
+            });
+            self.dma_cycles = 0;
+        }
+    }
+
+    /// Advance the divider by `cycles`, stepping the APU frame sequencer on
+    /// each falling edge of DIV bit 4
+    pub fn tick_div(&mut self, cycles: u32) {
+        let old = self.div_counter as u32;
+        // Bit 4 of DIV falls each time the divider passes a multiple of 0x2000
+        let period = 2 * SEQUENCER_DIV_BIT as u32;
+        for _ in 0..((old + cycles) / period - old / period) {
+            self.apu.step_sequencer();
+        }
+        self.div_counter = (old + cycles) as u16;
+        self.main_ram[IOREG_DIV] = (self.div_counter >> 8) as u8;
+    }
+
+    pub fn get_apu(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Request an interrupt, by latching its bit in the IF register
+    pub fn request_interrupt(&mut self, int: CpuInterrupt) {
+        let flags = self.main_ram[IOREG_IF] | int.mask();
+        self.sys_write(IOREG_IF, flags);
+    }
+
+    /// Set up the I/O registers as the boot ROM leaves them, and unmap the
+    /// boot ROM, for starting a cartridge without one
+    pub fn post_boot_init(&mut self) {
+        // Power the APU first, or its registers would ignore the writes
+        self.apu.write(apu::NR52, 0x80);
+        for &(addr, data) in DMG_POST_BOOT_IO.iter() {
+            if (apu::APU_START..=apu::APU_END).contains(&addr) {
+                self.apu.write(addr, data);
+            } else {
+                self.sys_write(addr, data);
             }
-            i += 1;
         }
-        return true;
+        self.div_counter = (self.main_ram[IOREG_DIV] as u16) << 8;
+        self.update_p1();
+        // Let the PPU pick up the new LCDC and STAT
+        self.lcd_dirty = true;
+        self.bios_readable = false;
+    }
+
+    pub fn set_bios_readable(&mut self) {
+        self.bios_readable = true;
     }
 
 }
@@ -360,7 +498,6 @@ pub struct RegData {
     l: u8,
     sp: u16,
     pc: u16,
-    flag: u8,
 }
 
 impl RegData {
@@ -377,7 +514,6 @@ impl RegData {
             l: 0,
             sp: 0xFFFE,
             pc: 0x000,
-            flag: 0,
         }
     }
 
@@ -391,7 +527,8 @@ impl RegData {
             Register::F => self.f,
             Register::H => self.h,
             Register::L => self.l,
-            Register::Flag => self.flag,
+            // F holds the flags in its upper nibble
+            Register::Flag => self.f,
             _ => panic!("Register not available for 8-bit read"),
         }
     }
@@ -415,7 +552,7 @@ impl RegData {
             Register::C => self.c = data,
             Register::D => self.d = data,
             Register::E => self.e = data,
-            Register::F => self.f = data,
+            Register::F => self.f = data & 0xF0,
             Register::H => self.h = data,
             Register::L => self.l = data,
             _ => panic!("Register not available for 8-bit write"),
@@ -427,7 +564,8 @@ impl RegData {
         match reg {
             Register::AF => {
                 self.a = hi;
-                self.f = lo;
+                // The low nibble of F is always zero
+                self.f = lo & 0xF0;
             },
             Register::BC => {
                 self.b = hi;
@@ -465,9 +603,9 @@ impl RegData {
             RegFlag::Carry => 0x10,
         };
         if (on) {
-            self.flag |= bit;
+            self.f |= bit;
         } else {
-            self.flag &= bit ^ 0xFF;
+            self.f &= bit ^ 0xFF;
         }
     }
 
@@ -478,7 +616,7 @@ impl RegData {
             RegFlag::HalfCarry => 0x20,
             RegFlag::Carry => 0x10,
         };
-        self.flag & bit != 0
+        self.f & bit != 0
     }
 
     pub fn advance_pc(&mut self) -> u16 {
@@ -504,3 +642,118 @@ impl RegData {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(ram: &mut AddressSpace, base: u16, seed: u8) {
+        for i in 0..DMA_LENGTH {
+            ram.sys_write(base + i, seed.wrapping_add(i as u8));
+        }
+    }
+
+    fn oam_matches(ram: &AddressSpace, seed: u8) -> bool {
+        (0..DMA_LENGTH).all(|i| ram[OAM_ADDR + i] == seed.wrapping_add(i as u8))
+    }
+
+    #[test]
+    fn div_steps_sequencer() {
+        let mut ram = AddressSpace::new();
+        ram.write(apu::NR52, 0x80);
+        ram.write(apu::NR22, 0xF0);
+        ram.write(apu::NR21, 63);
+        ram.write(apu::NR24, 0xC0);
+        ram.tick_div(0x1F00);
+        assert_eq!(ram.read(IOREG_DIV), 0x1F);
+        assert_eq!(ram.read(apu::NR52) & 0x02, 0x02);
+        // The first falling edge of bit 4 clocks the length counter out
+        ram.tick_div(0x100);
+        assert_eq!(ram.read(IOREG_DIV), 0x20);
+        assert_eq!(ram.read(apu::NR52) & 0x02, 0x00);
+        // Resetting DIV while bit 4 is set counts as a falling edge too
+        ram.write(apu::NR24, 0xC0);
+        ram.write(apu::NR21, 63);
+        // Past step 1, which doesn't clock lengths
+        ram.tick_div(0x3000);
+        assert_eq!(ram.read(apu::NR52) & 0x02, 0x02);
+        ram.write(IOREG_DIV, 0x12);
+        assert_eq!(ram.read(IOREG_DIV), 0x00);
+        assert_eq!(ram.read(apu::NR52) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn cart_dirty_on_accepted_writes() {
+        let mut ram = AddressSpace::new();
+        // No cartridge RAM at all
+        ram.write(0xA000, 0x12);
+        assert!(!ram.take_cart_dirty());
+        // MBC1 with 8 KiB of battery backed RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        ram.load_rom(&mut &rom[..]).unwrap();
+        ram.write(0xA000, 0x12);
+        assert!(!ram.take_cart_dirty());
+        ram.write(0x0000, 0x0A);
+        ram.write(0xA000, 0x12);
+        assert!(ram.take_cart_dirty());
+        assert_eq!(ram.read(0xA000), 0x12);
+    }
+
+    #[test]
+    fn oam_dma_timing() {
+        let mut ram = AddressSpace::new();
+        fill(&mut ram, 0xC000, 0x10);
+        ram.sys_write(0xFEA0, 0x55);
+        ram.sys_write(0xFF80, 0x42);
+        ram.write(IOREG_DMA, 0xC0);
+        // Starts once the writing instruction is done
+        assert!(!ram.dma_active());
+        ram.tick_dma(12);
+        assert!(ram.dma_active());
+        // Only I/O and HRAM are reachable meanwhile
+        assert_eq!(ram.read(0xC000), 0xFF);
+        assert_eq!(ram.read(0xFF80), 0x42);
+        ram.write(0xC000, 0x99);
+        assert_eq!(ram[0xC000], 0x10);
+        ram.tick_dma(DMA_BYTE_CYCLES * (DMA_LENGTH as u32 - 1));
+        assert!(ram.dma_active());
+        assert_eq!(ram[OAM_ADDR + DMA_LENGTH - 1], 0);
+        ram.tick_dma(DMA_BYTE_CYCLES);
+        assert!(!ram.dma_active());
+        assert!(oam_matches(&ram, 0x10));
+        // Exactly OAM is copied, and nothing past it
+        assert_eq!(ram[0xFEA0], 0x55);
+        assert_eq!(ram.read(0xC000), 0x10);
+    }
+
+    #[test]
+    fn oam_dma_echo_source() {
+        let mut ram = AddressSpace::new();
+        fill(&mut ram, 0xDE00, 0x80);
+        ram.write(IOREG_DMA, 0xFE);
+        ram.tick_dma(4);
+        ram.tick_dma(DMA_BYTE_CYCLES * DMA_LENGTH as u32);
+        assert!(oam_matches(&ram, 0x80));
+    }
+
+    #[test]
+    fn oam_dma_restart() {
+        let mut ram = AddressSpace::new();
+        fill(&mut ram, 0xC000, 0x10);
+        fill(&mut ram, 0xD000, 0x20);
+        ram.write(IOREG_DMA, 0xC0);
+        ram.tick_dma(4);
+        ram.tick_dma(DMA_BYTE_CYCLES * 100);
+        // Writing again starts over from the new source
+        ram.write(IOREG_DMA, 0xD0);
+        ram.tick_dma(8);
+        assert_eq!(ram[OAM_ADDR + 101], 0x10 + 101);
+        ram.tick_dma(DMA_BYTE_CYCLES * (DMA_LENGTH as u32 - 1));
+        assert!(ram.dma_active());
+        ram.tick_dma(DMA_BYTE_CYCLES);
+        assert!(!ram.dma_active());
+        assert!(oam_matches(&ram, 0x20));
+    }
+}
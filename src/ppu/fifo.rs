@@ -0,0 +1,467 @@
+//! Pixel FIFO renderer
+//!
+//! Draws a scanline one dot at a time, the way the hardware does during the
+//! pixel transfer. A fetcher reads tiles into the background FIFO, sprites
+//! stall it while their pixels are fetched into the sprite FIFO, and the
+//! window restarts it. Registers are read as each pixel is fetched or shifted
+//! out, so writes part way through a line take effect at the right pixel, and
+//! the length of the transfer varies like on hardware.
+
+use std::collections::VecDeque;
+
+use mem;
+use mem::AddressSpace;
+use super::{LCD_WIDTH, Window, SPRITE_ATTR_ADDR, SPRITE_TILE_ADDR, MAX_LINE_SPRITES};
+use super::{apply_palette, oam_scan, tile_row_pixel};
+
+/// Dots at the start of each line, spent on a tile fetch that is thrown away
+const STARTUP_DOTS: u32 = 6;
+
+/// Dots for the fetcher to read a tile number, then both bytes of a row
+const FETCH_DOTS: u8 = 6;
+
+/// Dots to fetch a sprite row, once the background fetch has finished
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Copy, Clone)]
+struct ObjPixel {
+    color:      u8,
+    palette:    u8,
+    behind_bg:  bool,
+}
+
+const OBJ_TRANSPARENT: ObjPixel = ObjPixel { color: 0, palette: 0, behind_bg: false };
+
+#[derive(Copy, Clone)]
+struct Sprite {
+    index:      u16,
+    x:          u8,
+    fetched:    bool,
+}
+
+pub struct FifoRenderer {
+    /// Next pixel of the LCD to be drawn
+    x:              u8,
+    /// Dots spent on this line's transfer
+    dots:           u32,
+    /// Pixels still to be dropped for fine scrolling
+    discard:        u8,
+    bg:             VecDeque<u8>,
+    obj:            VecDeque<ObjPixel>,
+    /// Dot of the current fetch, from 0 to FETCH_DOTS
+    fetch_step:     u8,
+    /// Tile column of the next fetch
+    fetch_x:        u8,
+    tile:           u8,
+    tile_row:       u8,
+    data_lo:        u8,
+    data_hi:        u8,
+    /// Fetching from the window, rather than the background
+    window:         bool,
+    /// Window state at the start of the line
+    window_state:   Window,
+    /// Sprites found by the OAM search, in OAM order
+    sprites:        Vec<Sprite>,
+    /// Sprite waiting on the fetcher, and the dots spent fetching it
+    sprite_fetch:   Option<(usize, u8)>,
+}
+
+impl FifoRenderer {
+
+    pub fn new() -> FifoRenderer {
+        FifoRenderer {
+            x: 0,
+            dots: 0,
+            discard: 0,
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            fetch_step: 0,
+            fetch_x: 0,
+            tile: 0,
+            tile_row: 0,
+            data_lo: 0,
+            data_hi: 0,
+            window: false,
+            window_state: Window::new(),
+            sprites: Vec::with_capacity(MAX_LINE_SPRITES),
+            sprite_fetch: None,
+        }
+    }
+
+    /// Dots spent on the current line so far
+    pub fn dots(&self) -> u32 {
+        self.dots
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.x as u32 >= LCD_WIDTH
+    }
+
+    /// Whether the window was started on the current line
+    pub fn drew_window(&self) -> bool {
+        self.window
+    }
+
+    /// Begin the pixel transfer for line `ly`, with the sprites found by the
+    /// OAM search. Checks WY against the line for the window.
+    pub fn start_line(&mut self, ram: &AddressSpace, ly: u8, window: &mut Window) {
+        window.start_line(ram, ly);
+        self.window_state = *window;
+        self.x = 0;
+        self.dots = 0;
+        self.discard = ram[mem::IOREG_SCX] & 0x07;
+        self.bg.clear();
+        self.obj.clear();
+        self.fetch_step = 0;
+        self.fetch_x = 0;
+        self.window = false;
+        self.sprite_fetch = None;
+        self.sprites.clear();
+        for i in oam_scan(ram, ly) {
+            self.sprites.push(Sprite {
+                index: i,
+                x: ram[SPRITE_ATTR_ADDR + i * 4 + 1],
+                fetched: false,
+            });
+        }
+    }
+
+    /// Run one dot of the transfer, drawing into the line `ly` of
+    /// `framebuffer`. Returns true once the line is finished.
+    pub fn dot(&mut self, ram: &AddressSpace, ly: u8, framebuffer: &mut [u8]) -> bool {
+        if self.is_done() {
+            return true;
+        }
+        self.dots += 1;
+        if self.dots <= STARTUP_DOTS {
+            return false;
+        }
+        let lcdc = ram[mem::IOREG_LCDC];
+
+        // A sprite at the current pixel stalls the pixel output. Its fetch
+        // waits for the background fetcher to reach its last dot, then the
+        // two overlap.
+        if self.sprite_fetch.is_none() && (lcdc & 0x02) != 0
+                && self.discard == 0 && !self.bg.is_empty() {
+            let x = self.x as i16;
+            // Sprites off the left edge all start at the first pixel, and
+            // are still fetched in order of X
+            let next = (0..self.sprites.len())
+                .filter(|&i| !self.sprites[i].fetched && self.sprites[i].x as i16 - 8 <= x)
+                .min_by_key(|&i| self.sprites[i].x);
+            if let Some(sprite) = next {
+                self.sprites[sprite].fetched = true;
+                self.sprite_fetch = Some((sprite, 0));
+            }
+        }
+        if let Some((sprite, spent)) = self.sprite_fetch {
+            let waiting = self.fetch_step < FETCH_DOTS - 1;
+            if self.fetch_step < FETCH_DOTS {
+                self.fetch_dot(ram, ly);
+            }
+            if waiting {
+                return false;
+            }
+            if spent + 1 < SPRITE_FETCH_DOTS {
+                self.sprite_fetch = Some((sprite, spent + 1));
+            } else {
+                self.load_sprite(ram, ly, sprite);
+                self.sprite_fetch = None;
+            }
+            return false;
+        }
+
+        // The window restarts the fetcher when it begins. Starting left of
+        // the screen, it takes the place of fine scrolling.
+        if !self.window {
+            match self.window_state.start_x(ram) {
+                Some(start) if self.x as i16 >= start => {
+                    self.window = true;
+                    self.bg.clear();
+                    self.fetch_step = 0;
+                    self.fetch_x = 0;
+                    self.discard = (-start).max(0) as u8;
+                },
+                _ => (),
+            }
+        }
+
+        // Shift out a pixel, then let the fetcher refill
+        if let Some(color) = self.bg.pop_front() {
+            if self.discard > 0 {
+                self.discard -= 1;
+            } else {
+                let obj = self.obj.pop_front().unwrap_or(OBJ_TRANSPARENT);
+                let bg_color = if (lcdc & 0x01) != 0 { color } else { 0 };
+                let shade = if obj.color != 0 && (lcdc & 0x02) != 0
+                        && !(obj.behind_bg && bg_color != 0) {
+                    apply_palette(ram[obj.palette_reg()], obj.color)
+                } else {
+                    apply_palette(ram[mem::IOREG_BGP], bg_color)
+                };
+                let row = ly as usize * LCD_WIDTH as usize;
+                framebuffer[row + self.x as usize] = shade;
+                self.x += 1;
+            }
+        }
+        self.fetch_dot(ram, ly);
+        self.is_done()
+    }
+
+    /// Advance the background fetcher by one dot
+    fn fetch_dot(&mut self, ram: &AddressSpace, ly: u8) {
+        let lcdc = ram[mem::IOREG_LCDC];
+        if self.fetch_step < FETCH_DOTS {
+            self.fetch_step += 1;
+            match self.fetch_step {
+                2 => {
+                    let (map_addr, col, row) = if self.window {
+                        let map = if (lcdc & 0x40) == 0 { 0x9800 } else { 0x9C00 };
+                        (map, self.fetch_x, self.window_state.line)
+                    } else {
+                        let map = if (lcdc & 0x08) == 0 { 0x9800 } else { 0x9C00 };
+                        let col = (ram[mem::IOREG_SCX] >> 3).wrapping_add(self.fetch_x) & 0x1F;
+                        (map, col, ly.wrapping_add(ram[mem::IOREG_SCY]))
+                    };
+                    self.tile = ram[map_addr + (row as u16 / 8) * 32 + col as u16];
+                    self.tile_row = row % 8;
+                },
+                4 => self.data_lo = ram[self.tile_addr(lcdc)],
+                6 => self.data_hi = ram[self.tile_addr(lcdc) + 1],
+                _ => (),
+            }
+        }
+        // The fetcher can only push once the FIFO has run dry
+        if self.fetch_step == FETCH_DOTS && self.bg.is_empty() {
+            for bit in (0..8).rev() {
+                let color = ((self.data_lo >> bit) & 0x01) | (((self.data_hi >> bit) & 0x01) << 1);
+                self.bg.push_back(color);
+            }
+            self.fetch_step = 0;
+            self.fetch_x = self.fetch_x.wrapping_add(1);
+        }
+    }
+
+    /// Address of the row being fetched, in the tile data selected by LCDC
+    fn tile_addr(&self, lcdc: u8) -> u16 {
+        let base = if (lcdc & 0x10) == 0 {
+            (0x9000 + (self.tile as i8 as i32) * 16) as u16
+        } else {
+            0x8000 + self.tile as u16 * 16
+        };
+        base + self.tile_row as u16 * 2
+    }
+
+    /// Mix a sprite's row into the sprite FIFO. Pixels already in the FIFO
+    /// belong to sprites with priority, so only transparent ones are replaced.
+    fn load_sprite(&mut self, ram: &AddressSpace, ly: u8, sprite: usize) {
+        let attr = SPRITE_ATTR_ADDR + self.sprites[sprite].index * 4;
+        let height = if (ram[mem::IOREG_LCDC] & 0x04) == 0 { 8 } else { 16 };
+        let y = ram[attr] as i16 - 16;
+        let x = ram[attr + 1] as i16 - 8;
+        let tile = ram[attr + 2] & if height > 8 { 0xFE } else { 0xFF };
+        let flag = ram[attr + 3];
+        let mut line = (ly as i16 - y) as u16;
+        if (flag & 0x40) != 0 {
+            line = height as u16 - 1 - line;
+        }
+        let tile_addr = SPRITE_TILE_ADDR + tile as u16 * 16 + line * 2;
+        while self.obj.len() < 8 {
+            self.obj.push_back(OBJ_TRANSPARENT);
+        }
+        for px in 0..8 {
+            let slot = x + px - self.x as i16;
+            if slot < 0 {
+                continue;
+            }
+            let bit = if (flag & 0x20) != 0 { px as u8 } else { 7 - px as u8 };
+            let color = tile_row_pixel(ram, tile_addr, bit);
+            let current = &mut self.obj[slot as usize];
+            if current.color == 0 && color != 0 {
+                *current = ObjPixel {
+                    color: color,
+                    palette: (flag >> 4) & 0x01,
+                    behind_bg: (flag & 0x80) != 0,
+                };
+            }
+        }
+    }
+
+}
+
+impl ObjPixel {
+
+    fn palette_reg(&self) -> u16 {
+        if self.palette == 0 { mem::IOREG_OBP0 } else { mem::IOREG_OBP1 }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem;
+    use mem::AddressSpace;
+    use ppu::{LCD_WIDTH, LCD_HEIGHT, Ppu, Window};
+
+    /// VRAM full of arbitrary tiles, with the LCD on and identity palettes
+    fn noisy_vram() -> AddressSpace {
+        let mut ram = AddressSpace::new();
+        let mut seed: u32 = 12345;
+        for addr in 0x8000..0xA000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ram.sys_write(addr, (seed >> 16) as u8);
+        }
+        for i in 0..0xA0 {
+            ram.sys_write(0xFE00 + i, 0);
+        }
+        ram.sys_write(mem::IOREG_LCDC, 0x93);
+        ram.sys_write(mem::IOREG_BGP, 0xE4);
+        ram.sys_write(mem::IOREG_OBP0, 0xE4);
+        ram.sys_write(mem::IOREG_OBP1, 0x1B);
+        ram
+    }
+
+    fn set_sprite(ram: &mut AddressSpace, i: u16, y: u8, x: u8, tile: u8, flag: u8) {
+        let attr = 0xFE00 + i * 4;
+        ram.sys_write(attr, y);
+        ram.sys_write(attr + 1, x);
+        ram.sys_write(attr + 2, tile);
+        ram.sys_write(attr + 3, flag);
+    }
+
+    /// Run a whole line, and return the dots it took
+    fn run_line(fifo: &mut FifoRenderer, ram: &AddressSpace, ly: u8, fb: &mut [u8]) -> u32 {
+        run_window_line(fifo, ram, ly, fb, &mut Window::new())
+    }
+
+    /// Run a whole line, carrying the window state over from earlier lines
+    fn run_window_line(fifo: &mut FifoRenderer, ram: &AddressSpace, ly: u8, fb: &mut [u8],
+                       window: &mut Window) -> u32 {
+        fifo.start_line(ram, ly, window);
+        while !fifo.dot(ram, ly, fb) {}
+        window.end_line(ram, fifo.drew_window());
+        fifo.dots()
+    }
+
+    /// Compare the FIFO renderer against the scanline renderer over a frame,
+    /// calling `change` before each line
+    fn compare_frame<F>(ram: &mut AddressSpace, mut change: F) where F: FnMut(&mut AddressSpace, u8) {
+        let mut scanline = Ppu::new();
+        let mut fifo = FifoRenderer::new();
+        let mut window = Window::new();
+        let mut fb = vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize];
+        for ly in 0..LCD_HEIGHT as u8 {
+            change(ram, ly);
+            scanline.render_line(ram, ly);
+            run_window_line(&mut fifo, ram, ly, &mut fb, &mut window);
+        }
+        let width = LCD_WIDTH as usize;
+        for ly in 0..LCD_HEIGHT as usize {
+            assert_eq!(&fb[ly * width..(ly + 1) * width],
+                       &scanline.framebuffer()[ly * width..(ly + 1) * width], "line {}", ly);
+        }
+    }
+
+    #[test]
+    fn transfer_length() {
+        let mut ram = noisy_vram();
+        let mut fifo = FifoRenderer::new();
+        let mut fb = vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize];
+        assert_eq!(run_line(&mut fifo, &ram, 0, &mut fb), 172);
+        // Fine scroll throws pixels away first
+        ram.sys_write(mem::IOREG_SCX, 0x03);
+        assert_eq!(run_line(&mut fifo, &ram, 0, &mut fb), 175);
+        ram.sys_write(mem::IOREG_SCX, 0x00);
+        // Sprites stall the transfer
+        set_sprite(&mut ram, 0, 16, 40, 1, 0);
+        let one = run_line(&mut fifo, &ram, 0, &mut fb);
+        assert!((172 + 6..=172 + 11).contains(&one), "{}", one);
+        set_sprite(&mut ram, 1, 16, 80, 1, 0);
+        assert!(run_line(&mut fifo, &ram, 0, &mut fb) > one);
+        // Only while sprites are enabled
+        ram.sys_write(mem::IOREG_LCDC, 0x91);
+        assert_eq!(run_line(&mut fifo, &ram, 0, &mut fb), 172);
+        // The window restarts the fetcher
+        ram.sys_write(mem::IOREG_LCDC, 0xB1);
+        ram.sys_write(mem::IOREG_WX, 87);
+        assert_eq!(run_line(&mut fifo, &ram, 0, &mut fb), 172 + 6);
+        // Left of the screen, it starts before the first fetch, and throws
+        // away its first pixels in place of the fine scroll
+        ram.sys_write(mem::IOREG_WX, 3);
+        assert_eq!(run_line(&mut fifo, &ram, 0, &mut fb), 172 + 4);
+    }
+
+    #[test]
+    fn matches_scanline_renderer() {
+        let mut ram = noisy_vram();
+        ram.sys_write(mem::IOREG_LCDC, 0xF3);
+        ram.sys_write(mem::IOREG_SCX, 0x25);
+        ram.sys_write(mem::IOREG_SCY, 0x13);
+        ram.sys_write(mem::IOREG_WX, 87);
+        ram.sys_write(mem::IOREG_WY, 40);
+        set_sprite(&mut ram, 0, 20, 12, 5, 0x00);
+        set_sprite(&mut ram, 1, 50, 100, 7, 0x30);
+        set_sprite(&mut ram, 2, 90, 4, 9, 0x40);
+        set_sprite(&mut ram, 3, 120, 165, 11, 0x80);
+        set_sprite(&mut ram, 4, 140, 60, 13, 0x90);
+        compare_frame(&mut ram, |_, _| ());
+    }
+
+    #[test]
+    fn sprite_priority_matches_scanline_renderer() {
+        let mut ram = noisy_vram();
+        // Crowded lines, with overlaps and sprites sharing an X position
+        let mut seed: u32 = 777;
+        for i in 0..40 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let y = 16 + (seed >> 8) as u8 % 24;
+            let x = (seed >> 16) as u8 % 40 * 4;
+            set_sprite(&mut ram, i, y, x, (seed >> 24) as u8, (seed as u8) & 0xF0);
+        }
+        compare_frame(&mut ram, |_, _| ());
+        ram.sys_write(mem::IOREG_LCDC, 0x97);
+        compare_frame(&mut ram, |_, _| ());
+    }
+
+    #[test]
+    fn window_matches_scanline_renderer() {
+        let mut ram = noisy_vram();
+        ram.sys_write(mem::IOREG_SCX, 0x05);
+        ram.sys_write(mem::IOREG_WY, 10);
+        compare_frame(&mut ram, |ram, ly| {
+            // Hidden for a few lines, then moved around, including the edges
+            let lcdc = if (30..40).contains(&ly) { 0xD1 } else { 0xF1 };
+            ram.sys_write(mem::IOREG_LCDC, lcdc);
+            let wx = match ly {
+                0...49 => 40,
+                50...59 => 3,
+                60...69 => 0,
+                70 => 166,
+                71...79 => 200,
+                _ => 7,
+            };
+            ram.sys_write(mem::IOREG_WX, wx);
+        });
+    }
+
+    #[test]
+    fn palette_write_mid_line() {
+        let mut ram = noisy_vram();
+        // Solid colour 3 background
+        for addr in 0x8000..0x9000 {
+            ram.sys_write(addr, 0xFF);
+        }
+        let mut fifo = FifoRenderer::new();
+        let mut fb = vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize];
+        fifo.start_line(&ram, 0, &mut Window::new());
+        // Startup and the first fetch, then 50 pixels
+        for _ in 0..12 + 50 {
+            fifo.dot(&ram, 0, &mut fb);
+        }
+        ram.sys_write(mem::IOREG_BGP, 0x24);
+        while !fifo.dot(&ram, 0, &mut fb) {}
+        assert!(fb[..50].iter().all(|&x| x == 3));
+        assert!(fb[50..160].iter().all(|&x| x == 0));
+    }
+}
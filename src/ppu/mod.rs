@@ -0,0 +1,825 @@
+use cpu::CpuInterrupt;
+use mem;
+use mem::AddressSpace;
+
+mod fifo;
+
+pub use self::fifo::FifoRenderer;
+
+pub const LCD_WIDTH: u32    = 160;
+pub const LCD_HEIGHT: u32   = 144;
+
+/// Period of the H-Blank in cycles, the length of one scanline
+pub const HBLANK_PERIOD: u64 = 456;
+
+/// Period of the V-Blank in cycles. V-Blank frequency is ~59.7 Hz
+pub const VBLANK_PERIOD: u64 = HBLANK_PERIOD * 154;
+
+/// Cycle within a frame at which the V-Blank begins, after 144 visible lines
+pub const VBLANK_OFFSET: u64 = HBLANK_PERIOD * 144;
+
+/// Length of the OAM search at the start of each visible line
+pub const OAM_SEARCH_CYCLES: u64 = 80;
+
+/// Length of the pixel transfer, after which the line is drawn
+pub const TRANSFER_CYCLES: u64 = 172;
+
+/// Rest of the scanline, after the pixel transfer
+pub const HBLANK_CYCLES: u64 = HBLANK_PERIOD - OAM_SEARCH_CYCLES - TRANSFER_CYCLES;
+
+/// Length of the OAM search period on the first line after the LCD is
+/// switched on, which is shorter than usual, and shows as mode 0
+pub const FIRST_LINE_CYCLES: u64 = OAM_SEARCH_CYCLES - 4;
+
+/// Dots the pixel FIFO renderer runs between steps, one machine cycle
+pub const FIFO_STEP_CYCLES: u64 = 4;
+
+/// How scanlines are drawn
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    /// Draw each line at once as the pixel transfer ends, which is fast, and
+    /// sees register writes made between lines
+    Scanline,
+    /// Draw dot by dot with a model of the pixel FIFO, which also sees writes
+    /// made part way through a line, and varies the transfer length
+    Fifo,
+}
+
+/// PPU mode, as shown in the low bits of STAT
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Hblank      = 0,
+    Vblank      = 1,
+    OamSearch   = 2,
+    Transfer    = 3,
+}
+
+/// Window state carried from one line to the next
+///
+/// The window only appears once LY has matched WY during the frame, and keeps
+/// its own line counter, which only advances on lines where it was drawn. So
+/// hiding the window for a few lines and showing it again carries on from the
+/// next row of its map, rather than skipping rows.
+#[derive(Copy, Clone, Debug)]
+pub struct Window {
+    /// LY has matched WY this frame
+    triggered:  bool,
+    /// Row of the window map to draw next
+    line:       u8,
+    /// The window was drawn with WX=166 on the previous line, which makes it
+    /// cover the whole of this one
+    wrap:       bool,
+}
+
+impl Window {
+
+    pub fn new() -> Window {
+        Window {
+            triggered: false,
+            line: 0,
+            wrap: false,
+        }
+    }
+
+    /// Check WY at the start of line `ly`
+    fn start_line(&mut self, ram: &AddressSpace, ly: u8) {
+        if ly == ram[mem::IOREG_WY] {
+            self.triggered = true;
+        }
+    }
+
+    /// Move the line counter on after a line, if the window was drawn on it
+    fn end_line(&mut self, ram: &AddressSpace, drawn: bool) {
+        if drawn {
+            self.line = self.line.wrapping_add(1);
+        }
+        self.wrap = drawn && ram[mem::IOREG_WX] == 166;
+    }
+
+    /// First pixel of the line covered by the window, or None if it is not
+    /// shown. With WX below 7 this is left of the screen, and the first few
+    /// pixels of the window are cut off.
+    fn start_x(&self, ram: &AddressSpace) -> Option<i16> {
+        if (ram[mem::IOREG_LCDC] & 0x20) == 0 || !self.triggered {
+            return None;
+        }
+        if self.wrap {
+            return Some(0);
+        }
+        let x = ram[mem::IOREG_WX] as i16 - 7;
+        if x < LCD_WIDTH as i16 { Some(x) } else { None }
+    }
+
+}
+
+const SPRITE_ATTR_ADDR: u16 = 0xFE00;
+const SPRITE_TILE_ADDR: u16 = 0x8000;
+
+/// Sprites that can be drawn on one line
+const MAX_LINE_SPRITES: usize = 10;
+
+/// Picture processing unit
+///
+/// Tracks the current scanline, and renders the LCD contents into a
+/// framebuffer one line at a time, so register writes between lines take
+/// effect part way down the screen. Each byte of the framebuffer is a DMG
+/// shade, from 0 (lightest) to 3 (darkest), with rows stored top to bottom.
+///
+/// The PPU is stepped by the machine at each mode change, and keeps the mode
+/// and LY=LYC bits of STAT up to date. The STAT interrupt is raised when any
+/// of its enabled sources becomes active, while none of the others were.
+///
+/// While LCDC bit 7 is clear the LCD is off: LY stays at 0 in mode 0, no
+/// interrupts are raised, and blank frames are produced at the usual rate.
+pub struct Ppu {
+    ly:             u8,
+    mode:           Mode,
+    /// The LCD is switched on
+    enabled:        bool,
+    /// The LCD was just switched on, and line 0 has yet to start its transfer
+    first_line:     bool,
+    /// Combined state of the enabled STAT interrupt sources
+    stat_line:      bool,
+    window:         Window,
+    renderer:       Renderer,
+    fifo:           FifoRenderer,
+    /// The current line is drawn by the FIFO renderer
+    fifo_line:      bool,
+    /// Cycles of the current transfer already stepped through
+    transfer_cycles: u64,
+    framebuffer:    Vec<u8>,
+    frame_ready:    bool,
+}
+
+impl Ppu {
+
+    pub fn new() -> Ppu {
+        Ppu {
+            ly: 0,
+            mode: Mode::OamSearch,
+            enabled: true,
+            first_line: false,
+            stat_line: false,
+            window: Window::new(),
+            renderer: Renderer::Scanline,
+            fifo: FifoRenderer::new(),
+            fifo_line: false,
+            transfer_cycles: 0,
+            framebuffer: vec![0; (LCD_WIDTH * LCD_HEIGHT) as usize],
+            frame_ready: false,
+        }
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Returns true once per completed frame
+    pub fn take_frame(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    pub fn get_ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn get_mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Choose the renderer, taking effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn get_renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Cycles from power on until the first call to `step`
+    pub fn first_step() -> u64 {
+        OAM_SEARCH_CYCLES
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Switch the LCD on or off to follow LCDC bit 7. Call after LCDC is
+    /// written. When it changes, returns the cycles until the next step, and
+    /// the step already scheduled must be dropped.
+    pub fn update_lcdc(&mut self, ram: &mut AddressSpace) -> Option<u64> {
+        let enabled = (ram[mem::IOREG_LCDC] & 0x80) != 0;
+        if enabled == self.enabled {
+            return None;
+        }
+        self.enabled = enabled;
+        self.ly = 0;
+        ram.sys_write(mem::IOREG_LY, 0);
+        self.window = Window::new();
+        self.mode = Mode::Hblank;
+        if enabled {
+            // The frame starts over, with a short first line. The sources
+            // already active don't count as a rising edge.
+            self.first_line = true;
+            self.refresh_stat(ram, false);
+            Some(FIRST_LINE_CYCLES)
+        } else {
+            self.first_line = false;
+            self.stat_line = false;
+            self.update_stat(ram);
+            for x in self.framebuffer.iter_mut() {
+                *x = 0;
+            }
+            self.frame_ready = true;
+            Some(VBLANK_PERIOD)
+        }
+    }
+
+    /// Move on to the next mode, and return the cycles until the following
+    /// step. Each visible line runs OAM search, pixel transfer, then H-Blank.
+    /// The scanline renderer draws the line as the pixel transfer ends, while
+    /// the FIFO renderer is stepped through the transfer a few dots at a time.
+    pub fn step(&mut self, ram: &mut AddressSpace) -> u64 {
+        if !self.enabled {
+            // Keep producing blank frames, so frontends carry on
+            self.frame_ready = true;
+            return VBLANK_PERIOD;
+        }
+        match self.mode {
+            Mode::OamSearch => self.start_transfer(ram),
+            Mode::Hblank if self.first_line => {
+                self.first_line = false;
+                self.start_transfer(ram)
+            },
+            Mode::Transfer if self.fifo_line => {
+                if !self.fifo.is_done() {
+                    return self.step_fifo(ram);
+                }
+                self.window.end_line(ram, self.fifo.drew_window());
+                self.set_mode(ram, Mode::Hblank);
+                HBLANK_PERIOD - OAM_SEARCH_CYCLES - self.transfer_cycles
+            },
+            Mode::Transfer => {
+                let ly = self.ly;
+                self.render_line(ram, ly);
+                self.set_mode(ram, Mode::Hblank);
+                HBLANK_CYCLES
+            },
+            Mode::Hblank => {
+                self.ly += 1;
+                ram.sys_write(mem::IOREG_LY, self.ly);
+                if self.ly as u32 == LCD_HEIGHT {
+                    self.frame_ready = true;
+                    ram.request_interrupt(CpuInterrupt::Vblank);
+                    self.set_mode(ram, Mode::Vblank);
+                    HBLANK_PERIOD
+                } else {
+                    self.set_mode(ram, Mode::OamSearch);
+                    OAM_SEARCH_CYCLES
+                }
+            },
+            Mode::Vblank => {
+                if self.ly >= 153 {
+                    self.ly = 0;
+                    self.window = Window::new();
+                    ram.sys_write(mem::IOREG_LY, self.ly);
+                    self.set_mode(ram, Mode::OamSearch);
+                    OAM_SEARCH_CYCLES
+                } else {
+                    self.ly += 1;
+                    ram.sys_write(mem::IOREG_LY, self.ly);
+                    self.update_stat(ram);
+                    HBLANK_PERIOD
+                }
+            },
+        }
+    }
+
+    fn start_transfer(&mut self, ram: &mut AddressSpace) -> u64 {
+        self.set_mode(ram, Mode::Transfer);
+        self.fifo_line = self.renderer == Renderer::Fifo;
+        if self.fifo_line {
+            self.fifo.start_line(ram, self.ly, &mut self.window);
+            self.transfer_cycles = 0;
+            self.step_fifo(ram)
+        } else {
+            TRANSFER_CYCLES
+        }
+    }
+
+    /// Run the FIFO renderer for the next few dots of the transfer
+    fn step_fifo(&mut self, ram: &mut AddressSpace) -> u64 {
+        for _ in 0..FIFO_STEP_CYCLES {
+            if self.fifo.dot(ram, self.ly, &mut self.framebuffer) {
+                break;
+            }
+        }
+        self.transfer_cycles += FIFO_STEP_CYCLES;
+        FIFO_STEP_CYCLES
+    }
+
+    fn set_mode(&mut self, ram: &mut AddressSpace, mode: Mode) {
+        self.mode = mode;
+        self.update_stat(ram);
+    }
+
+    /// Refresh the read only bits of STAT, and raise the STAT interrupt on a
+    /// rising edge of its sources. Call after LCDC, STAT or LYC are written.
+    pub fn update_stat(&mut self, ram: &mut AddressSpace) {
+        self.refresh_stat(ram, true);
+    }
+
+    /// Refresh STAT and the interrupt line, raising the interrupt on a rising
+    /// edge only if `raise` is set
+    fn refresh_stat(&mut self, ram: &mut AddressSpace, raise: bool) {
+        let coincidence = self.ly == ram[mem::IOREG_LYC];
+        let enables = ram[mem::IOREG_STAT] & 0x78;
+        let mut stat = 0x80 | enables | self.mode as u8;
+        if coincidence {
+            stat |= 0x04;
+        }
+        ram.sys_write(mem::IOREG_STAT, stat);
+        if !self.enabled {
+            return;
+        }
+        let line = (coincidence && (enables & 0x40) != 0)
+            || match self.mode {
+                Mode::Hblank => (enables & 0x08) != 0,
+                Mode::Vblank => (enables & 0x10) != 0,
+                Mode::OamSearch => (enables & 0x20) != 0,
+                Mode::Transfer => false,
+            };
+        if raise && line && !self.stat_line {
+            ram.request_interrupt(CpuInterrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    fn render_line(&mut self, ram: &AddressSpace, ly: u8) {
+        let lcdc_reg = ram[mem::IOREG_LCDC];
+        let win_map_addr            = if (lcdc_reg & 0x40) == 0 { 0x9800 } else { 0x9C00 };
+        let (tile_data, signed_idx) = if (lcdc_reg & 0x10) == 0 { (0x9000, true) }
+                                        else { (0x8000, false) };
+        let bg_map_addr             = if (lcdc_reg & 0x08) == 0 { 0x9800 } else { 0x9C00 };
+        let sprite_height           = if (lcdc_reg & 0x04) == 0 { 8 } else { 16 };
+        let sprite_on               = (lcdc_reg & 0x02) != 0;
+        let bg_on                   = (lcdc_reg & 0x01) != 0;
+        let scroll_y = ram[mem::IOREG_SCY];
+        let scroll_x = ram[mem::IOREG_SCX];
+        let bg_palette = ram[mem::IOREG_BGP];
+        self.window.start_line(ram, ly);
+        let win_start = self.window.start_x(ram);
+
+        // Colour indices before palette lookup, needed for sprite priority
+        let mut bg_color = [0u8; LCD_WIDTH as usize];
+
+        // Draw BG
+        if bg_on {
+            let y = ly.wrapping_add(scroll_y);
+            for x in 0..LCD_WIDTH as u8 {
+                let bx = x.wrapping_add(scroll_x);
+                bg_color[x as usize] = map_pixel(ram, bg_map_addr, tile_data, signed_idx, bx, y);
+            }
+        }
+
+        // Draw window. It is still counted as drawn while the BG is off.
+        if let Some(start) = win_start {
+            let y = self.window.line;
+            for x in start.max(0)..LCD_WIDTH as i16 {
+                let wx = (x - start) as u8;
+                bg_color[x as usize] = if bg_on {
+                    map_pixel(ram, win_map_addr, tile_data, signed_idx, wx, y)
+                } else {
+                    0
+                };
+            }
+        }
+        self.window.end_line(ram, win_start.is_some());
+
+        let row = ly as usize * LCD_WIDTH as usize;
+        let line = &mut self.framebuffer[row..row + LCD_WIDTH as usize];
+        for (pixel, &color) in line.iter_mut().zip(bg_color.iter()) {
+            *pixel = apply_palette(bg_palette, color);
+        }
+
+        // Draw sprites. Each pixel comes from the sprite with the lowest X,
+        // then the lowest OAM index, which isn't transparent there. Only then
+        // does its BG priority flag decide whether it shows over the BG.
+        if sprite_on {
+            let mut sprites = oam_scan(ram, ly);
+            sprites.sort_by_key(|&i| (ram[SPRITE_ATTR_ADDR + i * 4 + 1], i));
+            let mut drawn = [false; LCD_WIDTH as usize];
+            for &i in sprites.iter() {
+                let attr = SPRITE_ATTR_ADDR + i * 4;
+                let ypos = ram[attr] as i16 - 16;
+                let xpos = ram[attr + 1];
+                let tile = ram[attr + 2] & if sprite_height > 8 { 0xFE } else { 0xFF };
+                let flag = ram[attr + 3];
+                let palette = if (flag & 0x10) == 0 {
+                    ram[mem::IOREG_OBP0]
+                } else {
+                    ram[mem::IOREG_OBP1]
+                };
+                let behind_bg = (flag & 0x80) != 0;
+                let yflip = (flag & 0x40) != 0;
+                let xflip = (flag & 0x20) != 0;
+                let mut line = (ly as i16 - ypos) as u16;
+                if yflip {
+                    line = sprite_height as u16 - 1 - line;
+                }
+                let tile_addr = SPRITE_TILE_ADDR + (tile as u16) * 16 + line * 2;
+                for px in 0..8 {
+                    let x = xpos as i16 - 8 + px;
+                    if x < 0 || x >= LCD_WIDTH as i16 || drawn[x as usize] {
+                        continue;
+                    }
+                    let bit = if xflip { px as u8 } else { 7 - px as u8 };
+                    let color = tile_row_pixel(ram, tile_addr, bit);
+                    if color == 0 {
+                        continue;
+                    }
+                    drawn[x as usize] = true;
+                    if behind_bg && bg_color[x as usize] != 0 {
+                        continue;
+                    }
+                    self.framebuffer[row + x as usize] = apply_palette(palette, color);
+                }
+            }
+        }
+    }
+
+}
+
+/// OAM search for line `ly`: the OAM indices of the first ten sprites which
+/// cover the line, in OAM order. Sprites off the sides of the screen still
+/// count towards the limit.
+fn oam_scan(ram: &AddressSpace, ly: u8) -> Vec<u16> {
+    let height = if (ram[mem::IOREG_LCDC] & 0x04) == 0 { 8 } else { 16 };
+    let mut sprites = Vec::with_capacity(MAX_LINE_SPRITES);
+    for i in 0..40 {
+        let y = ram[SPRITE_ATTR_ADDR + i * 4] as i16 - 16;
+        if (ly as i16) < y || (ly as i16) >= y + height {
+            continue;
+        }
+        sprites.push(i);
+        if sprites.len() == MAX_LINE_SPRITES {
+            break;
+        }
+    }
+    sprites
+}
+
+/// Map a colour index through a DMG palette register
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+/// Colour index of bit `bit` in the tile row starting at `addr`
+fn tile_row_pixel(ram: &AddressSpace, addr: u16, bit: u8) -> u8 {
+    let lo = ram[addr];
+    let hi = ram[addr + 1];
+    ((lo >> bit) & 0x01) | (((hi >> bit) & 0x01) << 1)
+}
+
+/// Colour index of pixel (x, y) of a 256x256 tile map
+fn map_pixel(ram: &AddressSpace, map_addr: u16, tile_data: u16, signed_idx: bool, x: u8, y: u8) -> u8 {
+    let map_idx = (y as u16 / 8) * 32 + (x as u16 / 8);
+    let offset: i32 = if signed_idx {
+        ram[map_addr + map_idx] as i8 as i32
+    } else {
+        ram[map_addr + map_idx] as i32
+    };
+    let addr = (tile_data as i32 + offset * 16) as u16 + (y as u16 % 8) * 2;
+    tile_row_pixel(ram, addr, 7 - (x % 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem;
+    use mem::AddressSpace;
+
+    /// Tile 1 is solid colour 3, and the left half of the BG map uses it
+    fn striped_vram(ram: &mut AddressSpace) {
+        for i in 0..16 {
+            ram.sys_write(0x8010 + i, 0xFF);
+        }
+        for row in 0..32 {
+            for col in 0..16 {
+                ram.sys_write(0x9800 + row * 32 + col, 0x01);
+            }
+        }
+        ram.sys_write(mem::IOREG_LCDC, 0x91);
+        ram.sys_write(mem::IOREG_BGP, 0xE4);
+    }
+
+    /// BG of colour 1, and a window map of colour 0 on its first row of
+    /// tiles, then colour 3 on its second
+    fn window_vram(ram: &mut AddressSpace) {
+        for i in 0..16 {
+            ram.sys_write(0x8010 + i, 0xFF);
+            ram.sys_write(0x8020 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        }
+        for i in 0..0x400 {
+            ram.sys_write(0x9800 + i, 0x02);
+            ram.sys_write(0x9C00 + i, if (32..64).contains(&i) { 0x01 } else { 0x00 });
+        }
+        ram.sys_write(mem::IOREG_LCDC, 0xF1);
+        ram.sys_write(mem::IOREG_BGP, 0xE4);
+    }
+
+    /// Step until the start of the given line and mode, and return the cycle
+    /// it started on. `now` tracks the cycle of the next step.
+    fn run_to(ppu: &mut Ppu, ram: &mut AddressSpace, now: &mut u64, ly: u8, mode: Mode) -> u64 {
+        let mut start = *now;
+        while ppu.get_ly() != ly || ppu.get_mode() != mode {
+            start = *now;
+            *now += ppu.step(ram);
+        }
+        start
+    }
+
+    fn take_stat_interrupt(ram: &mut AddressSpace) -> bool {
+        let flags = ram.read(mem::IOREG_IF);
+        ram.write(mem::IOREG_IF, flags & !0x02);
+        (flags & 0x02) != 0
+    }
+
+    #[test]
+    fn scroll_changes_between_lines() {
+        let mut ram = AddressSpace::new();
+        striped_vram(&mut ram);
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Hblank);
+        ram.write(mem::IOREG_SCX, 0x80);
+        run_to(&mut ppu, &mut ram, &mut now, 1, Mode::Hblank);
+        let width = LCD_WIDTH as usize;
+        assert_eq!(ppu.framebuffer()[0], 3);
+        assert_eq!(ppu.framebuffer()[128], 0);
+        // The second line sees the new scroll, the first keeps the old one
+        assert_eq!(ppu.framebuffer()[width], 0);
+        assert_eq!(ppu.framebuffer()[width + 128], 3);
+    }
+
+    #[test]
+    fn mode_timings() {
+        let mut ram = AddressSpace::new();
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        let mut modes = Vec::new();
+        for _ in 0..4 {
+            modes.push((now, ppu.step(&mut ram), ram.read(mem::IOREG_STAT) & 0x03));
+            now += modes.last().unwrap().1;
+        }
+        assert_eq!(modes, vec![(80, 172, 3), (252, 204, 0), (456, 80, 2), (536, 172, 3)]);
+        assert_eq!(ram.read(mem::IOREG_LY), 1);
+        // V-Blank starts after 144 lines, and the frame is 154 lines long
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 144, Mode::Vblank), VBLANK_OFFSET);
+        assert_eq!(ram.read(mem::IOREG_STAT) & 0x03, 1);
+        assert!(ppu.take_frame());
+        assert_eq!(ram.read(mem::IOREG_IF) & 0x01, 0x01);
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 153, Mode::Vblank), HBLANK_PERIOD * 153);
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 0, Mode::OamSearch), VBLANK_PERIOD);
+    }
+
+    #[test]
+    fn lyc_coincidence() {
+        let mut ram = AddressSpace::new();
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        ram.write(mem::IOREG_LYC, 3);
+        ram.write(mem::IOREG_STAT, 0x40);
+        ppu.update_stat(&mut ram);
+        assert!(!take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 3, Mode::OamSearch);
+        assert_eq!(ram.read(mem::IOREG_STAT), 0xC6);
+        assert!(take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 4, Mode::OamSearch);
+        assert_eq!(ram.read(mem::IOREG_STAT) & 0x04, 0);
+        assert!(!take_stat_interrupt(&mut ram));
+        // Moving LYC onto the current line triggers straight away
+        ram.write(mem::IOREG_LYC, 4);
+        ppu.update_stat(&mut ram);
+        assert!(take_stat_interrupt(&mut ram));
+    }
+
+    #[test]
+    fn stat_sources() {
+        let mut ram = AddressSpace::new();
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        ram.write(mem::IOREG_STAT, 0x08);
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Hblank);
+        assert!(take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 1, Mode::Hblank);
+        assert!(take_stat_interrupt(&mut ram));
+        ram.write(mem::IOREG_STAT, 0x10);
+        run_to(&mut ppu, &mut ram, &mut now, 144, Mode::Vblank);
+        assert!(take_stat_interrupt(&mut ram));
+        // Mode 1 lasts all ten lines, with a single interrupt
+        run_to(&mut ppu, &mut ram, &mut now, 150, Mode::Vblank);
+        assert!(!take_stat_interrupt(&mut ram));
+        ram.write(mem::IOREG_STAT, 0x20);
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::OamSearch);
+        assert!(take_stat_interrupt(&mut ram));
+    }
+
+    #[test]
+    fn stat_blocking() {
+        let mut ram = AddressSpace::new();
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        // H-Blank holds the line high as LY=LYC starts on the next line
+        ram.write(mem::IOREG_LYC, 5);
+        ram.write(mem::IOREG_STAT, 0x48);
+        run_to(&mut ppu, &mut ram, &mut now, 4, Mode::Hblank);
+        assert!(take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 5, Mode::OamSearch);
+        assert!(!take_stat_interrupt(&mut ram));
+        // LY=LYC then holds it through the H-Blank of line 5
+        run_to(&mut ppu, &mut ram, &mut now, 5, Mode::Hblank);
+        assert!(!take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 6, Mode::Hblank);
+        assert!(take_stat_interrupt(&mut ram));
+    }
+
+    #[test]
+    fn window_line_counter() {
+        let mut ram = AddressSpace::new();
+        window_vram(&mut ram);
+        ram.write(mem::IOREG_WX, 7);
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        // Hide the window for lines 8 to 15
+        run_to(&mut ppu, &mut ram, &mut now, 7, Mode::Hblank);
+        ram.write(mem::IOREG_LCDC, 0xD1);
+        run_to(&mut ppu, &mut ram, &mut now, 15, Mode::Hblank);
+        ram.write(mem::IOREG_LCDC, 0xF1);
+        // Moving WY below LY keeps it going, once it has been triggered
+        ram.write(mem::IOREG_WY, 100);
+        run_to(&mut ppu, &mut ram, &mut now, 24, Mode::Hblank);
+        let width = LCD_WIDTH as usize;
+        let shade = |ly: usize| ppu.framebuffer()[ly * width];
+        assert_eq!(shade(7), 0);
+        assert_eq!(shade(8), 1);
+        assert_eq!(shade(15), 1);
+        // Line 16 carries on from the ninth row of the window
+        assert_eq!(shade(16), 3);
+        assert_eq!(shade(23), 3);
+        assert_eq!(shade(24), 0);
+    }
+
+    #[test]
+    fn window_x_edges() {
+        let mut ram = AddressSpace::new();
+        window_vram(&mut ram);
+        ram.write(mem::IOREG_WY, 0);
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        let width = LCD_WIDTH as usize;
+        // WX below 7 cuts off the left of the window
+        ram.write(mem::IOREG_WX, 0);
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Hblank);
+        assert_eq!(ppu.framebuffer()[0], 0);
+        run_to(&mut ppu, &mut ram, &mut now, 7, Mode::Hblank);
+        ram.write(mem::IOREG_WX, 3);
+        run_to(&mut ppu, &mut ram, &mut now, 8, Mode::Hblank);
+        assert_eq!(ppu.framebuffer()[8 * width], 3);
+        // WX=166 shows a single pixel, then the whole of the next line
+        ram.write(mem::IOREG_WX, 166);
+        run_to(&mut ppu, &mut ram, &mut now, 9, Mode::Hblank);
+        assert_eq!(ppu.framebuffer()[9 * width + 158], 1);
+        assert_eq!(ppu.framebuffer()[9 * width + 159], 3);
+        ram.write(mem::IOREG_WX, 200);
+        run_to(&mut ppu, &mut ram, &mut now, 10, Mode::Hblank);
+        assert_eq!(ppu.framebuffer()[10 * width], 3);
+        run_to(&mut ppu, &mut ram, &mut now, 11, Mode::Hblank);
+        assert_eq!(ppu.framebuffer()[11 * width], 1);
+    }
+
+    fn set_sprite(ram: &mut AddressSpace, i: u16, y: u8, x: u8, tile: u8, flag: u8) {
+        let attr = SPRITE_ATTR_ADDR + i * 4;
+        ram.sys_write(attr, y);
+        ram.sys_write(attr + 1, x);
+        ram.sys_write(attr + 2, tile);
+        ram.sys_write(attr + 3, flag);
+    }
+
+    #[test]
+    fn sprite_selection() {
+        let mut ram = AddressSpace::new();
+        // Tiles 1 to 3 are solid colours 3, 1 and 2
+        for i in 0..8 {
+            ram.sys_write(0x8010 + i * 2, 0xFF);
+            ram.sys_write(0x8011 + i * 2, 0xFF);
+            ram.sys_write(0x8020 + i * 2, 0xFF);
+            ram.sys_write(0x8031 + i * 2, 0xFF);
+        }
+        // Colour 1 BG on lines 40 to 47
+        for col in 0..32 {
+            ram.sys_write(0x9800 + 5 * 32 + col, 0x02);
+        }
+        ram.sys_write(mem::IOREG_LCDC, 0x93);
+        ram.sys_write(mem::IOREG_BGP, 0xE4);
+        ram.sys_write(mem::IOREG_OBP0, 0xE4);
+        let mut ppu = Ppu::new();
+        let width = LCD_WIDTH as usize;
+
+        // Only the first ten sprites on a line are drawn
+        for i in 0..12 {
+            set_sprite(&mut ram, i, 16, 8 + 12 * i as u8, 1, 0);
+        }
+        ppu.render_line(&ram, 0);
+        for i in 0..12 {
+            assert_eq!(ppu.framebuffer()[12 * i], if i < 10 { 3 } else { 0 }, "sprite {}", i);
+        }
+
+        // The lowest X wins, then the lowest OAM index
+        set_sprite(&mut ram, 20, 36, 50, 2, 0);
+        set_sprite(&mut ram, 21, 36, 46, 3, 0);
+        set_sprite(&mut ram, 22, 36, 100, 2, 0);
+        set_sprite(&mut ram, 23, 36, 100, 3, 0);
+        ppu.render_line(&ram, 20);
+        let line = &ppu.framebuffer()[20 * width..21 * width];
+        assert_eq!(&line[38..48], &[2, 2, 2, 2, 2, 2, 2, 2, 1, 1]);
+        assert_eq!(line[92], 1);
+
+        // A sprite behind the BG still hides the sprites under it
+        set_sprite(&mut ram, 24, 56, 20, 1, 0x80);
+        set_sprite(&mut ram, 25, 56, 22, 3, 0);
+        ppu.render_line(&ram, 40);
+        let line = &ppu.framebuffer()[40 * width..41 * width];
+        assert_eq!(&line[12..22], &[1, 1, 1, 1, 1, 1, 1, 1, 2, 2]);
+
+        // 8x16 sprites ignore the low bit of the tile index
+        ram.sys_write(mem::IOREG_LCDC, 0x97);
+        set_sprite(&mut ram, 26, 68, 80, 3, 0);
+        ppu.render_line(&ram, 52);
+        assert_eq!(ppu.framebuffer()[52 * width + 72], 1);
+        ppu.render_line(&ram, 60);
+        assert_eq!(ppu.framebuffer()[60 * width + 72], 2);
+    }
+
+    #[test]
+    fn lcd_off_and_on() {
+        let mut ram = AddressSpace::new();
+        striped_vram(&mut ram);
+        let mut ppu = Ppu::new();
+        let mut now = Ppu::first_step();
+        run_to(&mut ppu, &mut ram, &mut now, 10, Mode::Hblank);
+        ram.write(mem::IOREG_IF, 0);
+        ram.write(mem::IOREG_STAT, 0x08);
+        // Switching off holds LY at 0 in mode 0, and blanks the screen
+        ram.write(mem::IOREG_LCDC, 0x11);
+        assert_eq!(ppu.update_lcdc(&mut ram), Some(VBLANK_PERIOD));
+        assert_eq!(ppu.update_lcdc(&mut ram), None);
+        assert_eq!(ram.read(mem::IOREG_LY), 0);
+        assert_eq!(ram.read(mem::IOREG_STAT) & 0x03, 0);
+        assert!(ppu.framebuffer().iter().all(|&x| x == 0));
+        assert!(ppu.take_frame());
+        for _ in 0..3 {
+            assert_eq!(ppu.step(&mut ram), VBLANK_PERIOD);
+            assert!(ppu.take_frame());
+        }
+        assert_eq!(ram.read(mem::IOREG_LY), 0);
+        assert_eq!(ram.read(mem::IOREG_IF) & 0x03, 0);
+        // Switching on starts a new frame, with a shorter first line that
+        // stays in mode 0 until the transfer
+        ram.write(mem::IOREG_STAT, 0x00);
+        ram.write(mem::IOREG_LCDC, 0x91);
+        assert_eq!(ppu.update_lcdc(&mut ram), Some(FIRST_LINE_CYCLES));
+        assert_eq!(ram.read(mem::IOREG_STAT) & 0x03, 0);
+        let mut now = FIRST_LINE_CYCLES;
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Transfer), FIRST_LINE_CYCLES);
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 1, Mode::OamSearch), HBLANK_PERIOD - 4);
+        assert_eq!(run_to(&mut ppu, &mut ram, &mut now, 144, Mode::Vblank),
+                   VBLANK_OFFSET - 4);
+        assert_eq!(ram.read(mem::IOREG_IF) & 0x01, 0x01);
+    }
+
+    #[test]
+    fn lcd_on_with_hblank_source() {
+        let mut ram = AddressSpace::new();
+        let mut ppu = Ppu::new();
+        ram.write(mem::IOREG_LCDC, 0x11);
+        assert_eq!(ppu.update_lcdc(&mut ram), Some(VBLANK_PERIOD));
+        ram.write(mem::IOREG_STAT, 0x08);
+        ppu.update_stat(&mut ram);
+        ram.write(mem::IOREG_IF, 0);
+        // Starting in mode 0 is not a rising edge of the H-Blank source
+        ram.write(mem::IOREG_LCDC, 0x91);
+        assert_eq!(ppu.update_lcdc(&mut ram), Some(FIRST_LINE_CYCLES));
+        ppu.update_stat(&mut ram);
+        assert!(!take_stat_interrupt(&mut ram));
+        // The first real H-Blank still raises it
+        let mut now = FIRST_LINE_CYCLES;
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Transfer);
+        assert!(!take_stat_interrupt(&mut ram));
+        run_to(&mut ppu, &mut ram, &mut now, 0, Mode::Hblank);
+        assert!(take_stat_interrupt(&mut ram));
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use gameboy_rust::apu::DEFAULT_SAMPLE_RATE;
+use gameboy_rust::audio::{AudioSink, NullSink, WavSink};
+use gameboy_rust::machine::Machine;
+
+/// Sound queued ahead of the output device, in milliseconds. Enough to ride
+/// out a slow frame without crackling.
+#[cfg(feature = "audio")]
+pub const AUDIO_LATENCY_MS: u32 = 60;
+
+/// Sink used when none is asked for
+#[cfg(feature = "audio")]
+pub const DEFAULT_SINK: &'static str = "cpal";
+#[cfg(not(feature = "audio"))]
+pub const DEFAULT_SINK: &'static str = "null";
+
+/// Open an audio sink from a spec of `cpal`, `null`, or `wav=PATH`
+pub fn open_sink(spec: &str) -> Result<Box<dyn AudioSink + Send>, String> {
+    let mut parts = spec.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim();
+    let arg = parts.next().map(|s| s.trim());
+    match (name, arg) {
+        ("null", None) => Ok(Box::new(NullSink::new(DEFAULT_SAMPLE_RATE))),
+        ("wav", Some(path)) => {
+            let sink = try!(create_wav(Path::new(path)).map_err(|e| format!("{}: {}", path, e)));
+            Ok(Box::new(sink))
+        },
+        ("cpal", None) => open_cpal(),
+        _ => Err(format!("Unknown audio output \"{}\"", spec)),
+    }
+}
+
+/// Run `frames` frames as fast as possible, writing the sound to a WAV file at
+/// `path`. With `stems`, each channel is also written to a file of its own,
+/// such as `out.ch1.wav`.
+pub fn record_audio(machine: &mut Machine, path: &Path, frames: u32, stems: bool) -> io::Result<()> {
+    machine.set_sample_rate(DEFAULT_SAMPLE_RATE);
+    machine.set_stems(stems);
+    let mut mix = try!(create_wav(path));
+    let mut stem_sinks = Vec::new();
+    if stems {
+        for channel in 0..4 {
+            stem_sinks.push(try!(create_wav(&stem_path(path, channel))));
+        }
+    }
+    let mut samples = Vec::new();
+    for _ in 0..frames {
+        machine.run_frame();
+        samples.clear();
+        machine.take_samples(&mut samples);
+        try!(mix.write(&samples));
+        for (channel, sink) in stem_sinks.iter_mut().enumerate() {
+            samples.clear();
+            machine.take_stem_samples(channel, &mut samples);
+            try!(sink.write(&samples));
+        }
+    }
+    try!(mix.finish());
+    for sink in stem_sinks.iter_mut() {
+        try!(sink.finish());
+    }
+    Ok(())
+}
+
+fn create_wav(path: &Path) -> io::Result<WavSink<BufWriter<File>>> {
+    let file = try!(File::create(path));
+    WavSink::new(BufWriter::new(file), DEFAULT_SAMPLE_RATE)
+}
+
+/// Path of the stem for `channel`, from 0 to 3, next to the mix at `path`
+fn stem_path(path: &Path, channel: usize) -> PathBuf {
+    path.with_extension(format!("ch{}.wav", channel + 1))
+}
+
+#[cfg(feature = "audio")]
+fn open_cpal() -> Result<Box<dyn AudioSink + Send>, String> {
+    cpal_backend::open(AUDIO_LATENCY_MS).map(|sink| Box::new(sink) as Box<dyn AudioSink + Send>)
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_cpal() -> Result<Box<dyn AudioSink + Send>, String> {
+    Err("Built without audio output, enable the audio feature".to_string())
+}
+
+#[cfg(feature = "audio")]
+mod cpal_backend {
+    use std::thread;
+
+    use cpal;
+    use cpal::{EventLoop, StreamData, UnknownTypeOutputBuffer};
+
+    use gameboy_rust::audio::{sample_queue, QueueReader, QueueSink};
+
+    /// Play through the default output device, which is ALSA on Linux. The
+    /// device's callback runs on a thread of its own, pulling from the queue.
+    pub fn open(latency_ms: u32) -> Result<QueueSink, String> {
+        let device = match cpal::default_output_device() {
+            Some(d) => d,
+            None => return Err("No audio output device".to_string()),
+        };
+        let format = try!(device.default_output_format().map_err(|e| format!("{:?}", e)));
+        let event_loop = EventLoop::new();
+        let stream = try!(event_loop.build_output_stream(&device, &format)
+            .map_err(|e| format!("{:?}", e)));
+        event_loop.play_stream(stream);
+        let (sink, reader) = sample_queue(format.sample_rate.0, latency_ms);
+        let channels = format.channels as usize;
+        try!(thread::Builder::new()
+            .name("audio output".to_string())
+            .spawn(move || play(event_loop, reader, channels))
+            .map_err(|e| e.to_string()));
+        Ok(sink)
+    }
+
+    fn play(event_loop: EventLoop, mut reader: QueueReader, channels: usize) {
+        let mut stereo = Vec::new();
+        event_loop.run(move |_, data| {
+            match data {
+                StreamData::Output { buffer: UnknownTypeOutputBuffer::I16(mut buffer) } => {
+                    fill(&mut reader, &mut stereo, &mut buffer, channels, |x| x);
+                },
+                StreamData::Output { buffer: UnknownTypeOutputBuffer::U16(mut buffer) } => {
+                    fill(&mut reader, &mut stereo, &mut buffer, channels, |x| (x as i32 + 0x8000) as u16);
+                },
+                StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buffer) } => {
+                    fill(&mut reader, &mut stereo, &mut buffer, channels, |x| x as f32 / 32768.0);
+                },
+                _ => (),
+            }
+        });
+    }
+
+    /// Fill a device buffer of `channels` interleaved channels from the
+    /// stereo queue. Mono devices get both sides mixed, and any channels
+    /// past the first two are left silent.
+    fn fill<T, F>(reader: &mut QueueReader, stereo: &mut Vec<i16>, buffer: &mut [T], channels: usize, convert: F)
+        where T: Copy, F: Fn(i16) -> T
+    {
+        let frames = buffer.len() / channels;
+        stereo.resize(frames * 2, 0);
+        reader.read(stereo);
+        for (frame, lr) in buffer.chunks_mut(channels).zip(stereo.chunks(2)) {
+            if channels == 1 {
+                frame[0] = convert(((lr[0] as i32 + lr[1] as i32) / 2) as i16);
+            } else {
+                frame[0] = convert(lr[0]);
+                frame[1] = convert(lr[1]);
+                for x in frame[2..].iter_mut() {
+                    *x = convert(0);
+                }
+            }
+        }
+    }
+}